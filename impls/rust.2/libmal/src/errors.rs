@@ -1,3 +1,7 @@
+use rustyline::Editor;
+
+use crate::reader::read_str;
+
 #[derive(Clone, Copy, Debug)]
 pub enum ParseError {
     /// EOF is reached before a terminating double-quote is found
@@ -13,6 +17,47 @@ pub enum ParseError {
     KeyWithoutValue,
     /// EOF is reached when a Form is expected
     MissingForm,
+    /// A backslash in a string is followed by something other than an escape
+    UnknownEscape(char),
+    /// A `\u` escape is not followed by four hex digits naming a character
+    InvalidUnicodeEscape,
+    /// A `#(` appears inside another one
+    NestedFnLiteral,
+    /// No reader function is registered for a tagged literal's tag
+    UnknownTag,
+    /// A reader function rejected the form it was given
+    InvalidTaggedLiteral,
+}
+
+impl ParseError {
+    /// Whether more input could complete the form, because a string or a
+    /// collection was still open when the input ran out
+    pub fn is_incomplete(&self) -> bool {
+        matches!(
+            self,
+            ParseError::UnbalancedString
+                | ParseError::UnbalancedParens
+                | ParseError::UnbalancedSquareBrackets
+                | ParseError::UnbalancedCurlyBrackets
+        )
+    }
+}
+
+/// Reads lines until they hold a complete form, showing a continuation
+/// prompt while one is still open, and records it as one history entry.
+/// With `raw` (the run script's `--raw`, for the test harness) every line
+/// is read on its own.
+pub fn read_input(rl: &mut Editor<()>, raw: bool) -> rustyline::Result<String> {
+    let mut input = rl.readline("user> ")?;
+    while !raw && matches!(read_str(&input), Err(e) if e.is_incomplete()) {
+        // piped lines already end in one
+        if !input.ends_with('\n') {
+            input.push('\n');
+        }
+        input.push_str(&rl.readline("  ... ")?);
+    }
+    rl.add_history_entry(&input);
+    Ok(input)
 }
 
 impl From<&ParseError> for ParseError {
@@ -34,9 +79,16 @@ impl std::fmt::Display for ParseError {
                 ParseError::UnexpectedCloseParen => "unexpected close paren",
                 ParseError::UnexpectedCloseSquareBracket => "unexpected close square bracket",
                 ParseError::UnexpectedCloseCurlyBracket => "unexpected close curly bracket",
-                ParseError::UnexpectedCompound => "compound value used as a key in a map",
+                ParseError::UnexpectedCompound => "compound value used as a map key or set element",
                 ParseError::KeyWithoutValue => "found key without corresponding value",
                 ParseError::MissingForm => "reached EOF while searching for form",
+                ParseError::UnknownEscape(c) => {
+                    return write!(f, "unknown escape sequence \\{}", c);
+                }
+                ParseError::InvalidUnicodeEscape => "invalid unicode escape",
+                ParseError::NestedFnLiteral => "nested #()s are not allowed",
+                ParseError::UnknownTag => "no reader function for tag",
+                ParseError::InvalidTaggedLiteral => "invalid tagged literal",
             }
         )
     }
@@ -1,5 +1,18 @@
+use super::reader::ESCAPES;
 use super::{Atom, Compound, Form};
 
+/// Escapes a string so the reader reads it back, writing control
+/// characters without an escape of their own as `\uXXXX`
+fn escape_str(s: &str) -> String {
+    s.chars()
+        .map(|c| match ESCAPES.iter().find(|(_, u)| *u == c) {
+            Some((name, _)) => format!("\\{}", name),
+            None if c.is_control() => format!("\\u{:04x}", c as u32),
+            None => c.to_string(),
+        })
+        .collect()
+}
+
 fn pr_compound(compound: &Compound, print_readably: bool) -> String {
     match compound {
         Compound::List(l) => {
@@ -33,6 +46,15 @@ fn pr_compound(compound: &Compound, print_readably: bool) -> String {
                     .join(" ")
             )
         }
+        Compound::Set(s) => {
+            format!(
+                "#{{{}}}",
+                s.into_iter()
+                    .map(|a| pr_atom(a, print_readably))
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            )
+        }
         Compound::Fn(f) => match &f.code {
             Some(code) => pr_str(&code.borrow(), print_readably),
             None => "[built-in function]".to_owned(),
@@ -46,9 +68,7 @@ pub fn pr_atom(atom: &Atom, print_readably: bool) -> String {
         Atom::String(s) => format!(
             r#""{}""#,
             if print_readably {
-                s.replace('\\', "\\\\")
-                    .replace('"', "\\\"")
-                    .replace('\n', "\\n")
+                escape_str(s)
             } else {
                 s.clone()
             }
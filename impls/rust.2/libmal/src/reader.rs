@@ -1,10 +1,111 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::iter::Peekable;
 
-use super::{Atom, Form, Object, ParseError, Token};
+use super::{Atom, AtomSet, Compound, Form, Object, ObjectMap, ObjectVec, ParseError, Token};
+
+/// String escapes shared with the printer: the character after the
+/// backslash and the character it stands for. Any other character can be
+/// written as `\uXXXX`.
+pub const ESCAPES: [(char, char); 6] = [
+    ('"', '"'),
+    ('\\', '\\'),
+    ('n', '\n'),
+    ('t', '\t'),
+    ('r', '\r'),
+    ('0', '\0'),
+];
 
 pub struct Reader<'a> {
     input: Peekable<Box<dyn Iterator<Item = char> + 'a>>,
+    /// How many parens are open
+    depth: usize,
+    /// The depth outside the `#(` being read, if any
+    fn_literal: Option<usize>,
+}
+
+/// Reads a tagged literal from the form following its tag
+pub type TagReader = fn(Object) -> Result<Object, ParseError>;
+
+thread_local! {
+    static TAGS: RefCell<HashMap<String, TagReader>> = RefCell::new(default_tags());
+}
+
+/// mal has no date or uuid types, so `#inst` and `#uuid` check the literal
+/// and read it as a string
+fn default_tags() -> HashMap<String, TagReader> {
+    let mut tags = HashMap::new();
+    tags.insert("inst".to_string(), read_inst as TagReader);
+    tags.insert("uuid".to_string(), read_uuid as TagReader);
+    tags
+}
+
+/// Makes `#tag form` read as whatever `f` returns for the form
+pub fn register_tag<S: AsRef<str>>(tag: S, f: TagReader) {
+    TAGS.with(|tags| tags.borrow_mut().insert(tag.as_ref().to_string(), f));
+}
+
+fn read_string_literal(form: Object, valid: fn(&str) -> bool) -> Result<Object, ParseError> {
+    match &*form.borrow() {
+        Form::Atom(Atom::String(s)) if valid(s) => (),
+        _ => return Err(ParseError::InvalidTaggedLiteral),
+    }
+    Ok(form)
+}
+
+fn read_inst(form: Object) -> Result<Object, ParseError> {
+    read_string_literal(form, is_timestamp)
+}
+
+fn read_uuid(form: Object) -> Result<Object, ParseError> {
+    read_string_literal(form, |s| {
+        s.len() == 36
+            && s.chars().enumerate().all(|(i, c)| match i {
+                8 | 13 | 18 | 23 => c == '-',
+                _ => c.is_ascii_hexdigit(),
+            })
+    })
+}
+
+/// Strips a prefix matching `shape`, in which `d` stands for any digit
+fn strip_shape<'a>(s: &'a str, shape: &str) -> Option<&'a str> {
+    let head = s.get(..shape.len())?;
+    let matches = head.chars().zip(shape.chars()).all(|(c, p)| match p {
+        'd' => c.is_ascii_digit(),
+        _ => c == p,
+    });
+    if matches {
+        Some(&s[shape.len()..])
+    } else {
+        None
+    }
+}
+
+/// Whether `s` is an RFC 3339 date, optionally followed by a time of day
+/// and an offset
+fn is_timestamp(s: &str) -> bool {
+    let mut s = match strip_shape(s, "dddd-dd-dd") {
+        Some(s) => s,
+        None => return false,
+    };
+    if let Some(time) = strip_shape(s, "Tdd:dd") {
+        s = time;
+        if let Some(secs) = strip_shape(s, ":dd") {
+            s = secs;
+            if let Some(frac) = s.strip_prefix('.') {
+                s = frac.trim_start_matches(|c: char| c.is_ascii_digit());
+                if s.len() == frac.len() {
+                    return false;
+                }
+            }
+        }
+        s = s
+            .strip_prefix('Z')
+            .or_else(|| strip_shape(s, "+dd:dd"))
+            .or_else(|| strip_shape(s, "-dd:dd"))
+            .unwrap_or(s);
+    }
+    s.is_empty()
 }
 
 pub fn read_str(input: &String) -> Result<Object, ParseError> {
@@ -19,7 +120,18 @@ fn peek_token<'a>(
     Ok(reader.peek().as_ref().ok_or(none_error)?.as_ref()?)
 }
 
+/// Skips the forms commented out with `#_`
+fn skip_discarded(reader: &mut Peekable<Reader>) -> Result<(), ParseError> {
+    while let Some(Ok(Token::Dispatch('_'))) = reader.peek() {
+        reader.next();
+        read_form(reader)?;
+        reader.next();
+    }
+    Ok(())
+}
+
 pub fn read_form(reader: &mut Peekable<Reader>) -> Result<Object, ParseError> {
+    skip_discarded(reader)?;
     let token = reader.peek().ok_or(ParseError::MissingForm)?.as_ref()?;
 
     match token {
@@ -51,6 +163,25 @@ pub fn read_form(reader: &mut Peekable<Reader>) -> Result<Object, ParseError> {
         Token::SpecialCharacter(c) => {
             panic!("somehow parsed invalid special character {}", c)
         }
+        Token::Dispatch('{') => {
+            reader.next();
+            Ok(Form::set(read_set(reader)?).into())
+        }
+        Token::Dispatch('(') => {
+            reader.next();
+            Ok(fn_literal(read_list(
+                reader,
+                ')',
+                ParseError::UnbalancedParens,
+            )?))
+        }
+        Token::Dispatch(c) => panic!("somehow parsed invalid dispatch character {}", c),
+        Token::Tag(tag) => {
+            let f = TAGS.with(|tags| tags.borrow().get(tag).copied());
+            reader.next();
+            let form = read_form(reader)?;
+            f.ok_or(ParseError::UnknownTag)?(form)
+        }
         Token::SpecialTwoCharacter => Ok(call("splice-unquote", reader, 1)?),
         Token::Comment(_) => {
             reader.next();
@@ -79,15 +210,16 @@ fn read_list(
     reader: &mut Peekable<Reader>,
     end_char: char,
     unbalanced_error: ParseError,
-) -> Result<Vec<Object>, ParseError> {
-    let mut out = Vec::new();
+) -> Result<ObjectVec, ParseError> {
+    let mut out = ObjectVec::new();
 
     loop {
+        skip_discarded(reader)?;
         let token = peek_token(reader, unbalanced_error)?;
 
         match token {
             Token::SpecialCharacter(c) if *c == end_char => break,
-            _ => out.push(read_form(reader)?),
+            _ => out.push_back(read_form(reader)?),
         }
         reader.next();
     }
@@ -95,10 +227,11 @@ fn read_list(
     Ok(out)
 }
 
-fn read_map(reader: &mut Peekable<Reader>) -> Result<HashMap<Atom, Object>, ParseError> {
-    let mut map = HashMap::new();
+fn read_map(reader: &mut Peekable<Reader>) -> Result<ObjectMap, ParseError> {
+    let mut map = ObjectMap::new();
 
     loop {
+        skip_discarded(reader)?;
         let key = match peek_token(reader, ParseError::UnbalancedCurlyBrackets)? {
             Token::SpecialCharacter('}') => break,
             _ => read_form(reader)?,
@@ -110,6 +243,7 @@ fn read_map(reader: &mut Peekable<Reader>) -> Result<HashMap<Atom, Object>, Pars
             Form::Compound(_) => return Err(ParseError::UnexpectedCompound),
         };
 
+        skip_discarded(reader)?;
         let value = match peek_token(reader, ParseError::UnbalancedCurlyBrackets)? {
             Token::SpecialCharacter('}') => return Err(ParseError::KeyWithoutValue),
             _ => read_form(reader)?,
@@ -122,6 +256,79 @@ fn read_map(reader: &mut Peekable<Reader>) -> Result<HashMap<Atom, Object>, Pars
     Ok(map)
 }
 
+fn read_set(reader: &mut Peekable<Reader>) -> Result<AtomSet, ParseError> {
+    read_list(reader, '}', ParseError::UnbalancedCurlyBrackets)?
+        .into_iter()
+        .map(|element| match &*element.borrow() {
+            Form::Atom(a) => Ok(a.clone()),
+            Form::Compound(_) => Err(ParseError::UnexpectedCompound),
+        })
+        .collect()
+}
+
+/// `#(...)` reads as `(fn* [%1 .. %n & %&] (...))`, n being the highest `%n`
+/// the body uses. A bare `%` is `%1`.
+fn fn_literal(body: ObjectVec) -> Object {
+    let (mut n, mut rest) = (0, false);
+    let body = fn_literal_args(&Form::list(body).into(), &mut n, &mut rest);
+    let mut params: ObjectVec = (1..=n)
+        .map(|i| Form::symbol(format!("%{}", i)).into())
+        .collect();
+    if rest {
+        params.push_back(Form::symbol("&").into());
+        params.push_back(Form::symbol("%&").into());
+    }
+    let fun = vec![
+        Form::symbol("fn*").into(),
+        Form::vector(params).into(),
+        body,
+    ];
+    Form::list(fun.into()).into()
+}
+
+/// Renames `%` to `%1` throughout a form, noting the highest `%n` and any `%&`
+fn fn_literal_args(form: &Object, n: &mut usize, rest: &mut bool) -> Object {
+    let renamed = match &*form.borrow() {
+        Form::Atom(a) => Form::Atom(fn_literal_atom(a, n, rest)),
+        Form::Compound(Compound::List(l)) => {
+            Form::list(l.iter().map(|x| fn_literal_args(x, n, rest)).collect())
+        }
+        Form::Compound(Compound::Vector(v)) => {
+            Form::vector(v.iter().map(|x| fn_literal_args(x, n, rest)).collect())
+        }
+        Form::Compound(Compound::Map(m)) => Form::map(
+            m.iter()
+                .map(|(k, v)| (fn_literal_atom(k, n, rest), fn_literal_args(v, n, rest)))
+                .collect(),
+        ),
+        Form::Compound(Compound::Set(s)) => {
+            Form::set(s.iter().map(|x| fn_literal_atom(x, n, rest)).collect())
+        }
+        Form::Compound(Compound::Fn(_)) => return form.clone(),
+    };
+    renamed.into()
+}
+
+fn fn_literal_atom(atom: &Atom, n: &mut usize, rest: &mut bool) -> Atom {
+    match atom {
+        Atom::Symbol(s) if s == "%" => {
+            *n = (*n).max(1);
+            Atom::Symbol("%1".to_string())
+        }
+        Atom::Symbol(s) if s == "%&" => {
+            *rest = true;
+            atom.clone()
+        }
+        Atom::Symbol(s) if s.starts_with('%') => {
+            if let Ok(i) = s[1..].parse::<usize>() {
+                *n = (*n).max(i);
+            }
+            atom.clone()
+        }
+        _ => atom.clone(),
+    }
+}
+
 fn call<S: AsRef<str>>(
     fun: S,
     reader: &mut Peekable<Reader>,
@@ -134,7 +341,7 @@ fn call<S: AsRef<str>>(
         l.push(read_form(reader)?)
     }
 
-    Ok(Form::list(l).into())
+    Ok(Form::list(l.into()).into())
 }
 
 /// with-meta uses its two arguments in reverse order, so we need to do this specially
@@ -152,13 +359,15 @@ fn with_meta(reader: &mut Peekable<Reader>) -> Result<Object, ParseError> {
     l.push(arg1);
     l.push(arg2);
 
-    Ok(Form::list(l).into())
+    Ok(Form::list(l.into()).into())
 }
 
 impl<'a> Reader<'a> {
     pub fn new(input: impl Iterator<Item = char> + 'a) -> Self {
         Reader {
             input: std::iter::Iterator::peekable(Box::new(input)),
+            depth: 0,
+            fn_literal: None,
         }
     }
 
@@ -171,26 +380,34 @@ impl<'a> Reader<'a> {
                     return Err(ParseError::UnbalancedString);
                 }
                 Some(c) if c == '"' => return Ok(Token::String(out)),
-                Some(c) if c == '\\' && self.input.peek() == Some(&'"') => {
-                    out.push('"');
-                    // Advance again to get past the escaped double-quote
-                    self.input.next();
-                }
-                Some(c) if c == '\\' && self.input.peek() == Some(&'\\') => {
-                    out.push('\\');
-                    // Advance again to get past the escaped double-quote
-                    self.input.next();
-                }
-                Some(c) if c == '\\' && self.input.peek() == Some(&'n') => {
-                    out.push('\n');
-                    // Advance again to get past the escaped double-quote
-                    self.input.next();
-                }
+                Some('\\') => out.push(self.consume_escape()?),
                 Some(c) => out.push(c),
             };
         }
     }
 
+    /// Reads the rest of an escape sequence, after its backslash
+    fn consume_escape(&mut self) -> Result<char, ParseError> {
+        match self.input.next() {
+            None => Err(ParseError::UnbalancedString),
+            Some('u') => {
+                let hex: String = self.input.by_ref().take(4).collect();
+                if hex.len() != 4 {
+                    return Err(ParseError::InvalidUnicodeEscape);
+                }
+                u32::from_str_radix(&hex, 16)
+                    .ok()
+                    .and_then(std::char::from_u32)
+                    .ok_or(ParseError::InvalidUnicodeEscape)
+            }
+            Some(e) => ESCAPES
+                .iter()
+                .find(|(name, _)| *name == e)
+                .map(|(_, c)| *c)
+                .ok_or(ParseError::UnknownEscape(e)),
+        }
+    }
+
     fn consume_until(&mut self, first: char, pred: impl Fn(char) -> bool) -> String {
         let mut out = String::new();
         out.push(first);
@@ -230,6 +447,44 @@ impl<'a> Iterator for Reader<'a> {
 
                 Some(Ok(Token::SpecialTwoCharacter))
             }
+            Some(c) if c == '#' && self.input.peek() == Some(&'{') => {
+                // Eat the '{'
+                self.input.next();
+
+                Some(Ok(Token::Dispatch('{')))
+            }
+            Some(c) if c == '#' && self.input.peek() == Some(&'_') => {
+                // Eat the '_'
+                self.input.next();
+
+                Some(Ok(Token::Dispatch('_')))
+            }
+            Some(c) if c == '#' && self.input.peek() == Some(&'(') => {
+                // Eat the '('
+                self.input.next();
+
+                if self.fn_literal.is_some() {
+                    return Some(Err(ParseError::NestedFnLiteral));
+                }
+                self.fn_literal = Some(self.depth);
+                self.depth += 1;
+                Some(Ok(Token::Dispatch('(')))
+            }
+            Some(c) if c == '#' => Some(Ok(Token::Tag(
+                self.consume_until(c, |c| c.is_whitespace() || ",[]{}()'`~^@\"".contains(c))[1..]
+                    .to_string(),
+            ))),
+            Some(c) if c == '(' => {
+                self.depth += 1;
+                Some(Ok(Token::SpecialCharacter(c)))
+            }
+            Some(c) if c == ')' => {
+                self.depth = self.depth.saturating_sub(1);
+                if self.fn_literal == Some(self.depth) {
+                    self.fn_literal = None;
+                }
+                Some(Ok(Token::SpecialCharacter(c)))
+            }
             Some(c) if "[]{}()'`~^@".contains(c) => Some(Ok(Token::SpecialCharacter(c))),
             Some(c) if c == '"' => Some(self.consume_string()),
             Some(c) if c == ';' => Some(Ok(Token::Comment(self.consume_until(c, |c| c == '\n')))),
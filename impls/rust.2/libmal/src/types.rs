@@ -1,5 +1,4 @@
 use std::cell::RefCell;
-use std::collections::HashMap;
 use std::rc::Rc;
 
 use super::errors::RuntimeError;
@@ -10,6 +9,11 @@ pub enum Token {
     SpecialTwoCharacter,
     /// One of ``[]{}()'`~^@``
     SpecialCharacter(char),
+    /// A `#` followed by the character selecting how to read the next form,
+    /// like the `{` of a set
+    Dispatch(char),
+    /// A `#` followed by the name of the reader function for the next form
+    Tag(String),
     /// A double-quoted string
     String(String),
     /// A sequence of characters starting with a ;
@@ -37,10 +41,18 @@ pub enum Atom {
     False,
 }
 
+/// Persistent sequence backing lists and vectors; clones share structure
+pub type ObjectVec = im_rc::Vector<Object>;
+/// Persistent map backing hash maps; clones share structure
+pub type ObjectMap = im_rc::HashMap<Atom, Object>;
+/// Persistent set backing sets; like map keys, elements are atoms
+pub type AtomSet = im_rc::HashSet<Atom>;
+
 pub enum Compound {
-    List(Vec<Object>),
-    Vector(Vec<Object>),
-    Map(HashMap<Atom, Object>),
+    List(ObjectVec),
+    Vector(ObjectVec),
+    Map(ObjectMap),
+    Set(AtomSet),
     Fn(MalFn),
 }
 
@@ -73,18 +85,22 @@ impl Form {
         Form::Atom(Atom::Keyword(s.as_ref().to_string()))
     }
 
-    pub fn list(l: Vec<Object>) -> Form {
+    pub fn list(l: ObjectVec) -> Form {
         Form::Compound(Compound::List(l))
     }
 
-    pub fn vector(v: Vec<Object>) -> Form {
+    pub fn vector(v: ObjectVec) -> Form {
         Form::Compound(Compound::Vector(v))
     }
 
-    pub fn map(m: HashMap<Atom, Object>) -> Form {
+    pub fn map(m: ObjectMap) -> Form {
         Form::Compound(Compound::Map(m))
     }
 
+    pub fn set(s: AtomSet) -> Form {
+        Form::Compound(Compound::Set(s))
+    }
+
     pub fn builtin(f: impl Fn(&[Object]) -> Object + 'static) -> Form {
         Form::Compound(Compound::Fn(MalFn {
             exec: Box::new(f),
@@ -5,16 +5,16 @@ use rustyline::Editor;
 
 use libmal::printer::pr_str;
 use libmal::reader::read_str;
-use libmal::{Object, ParseError};
+use libmal::{read_input, Object, ParseError};
 
 fn main() {
     let mut rl = Editor::<()>::new();
+    let raw = std::env::args().nth(1).as_deref() == Some("--raw");
 
     loop {
-        let readline = rl.readline("user> ");
+        let readline = read_input(&mut rl, raw);
         match readline {
             Ok(line) => {
-                rl.add_history_entry(&line);
                 rep(line);
             }
             Err(ReadlineError::Interrupted) => std::process::exit(1),
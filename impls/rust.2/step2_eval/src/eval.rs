@@ -12,7 +12,8 @@ pub fn eval(ast: Object, environment: &mut Environment) -> Result<Object, Box<dy
             if let Form::Compound(Compound::List(l)) =
                 &*eval_ast(ast.clone(), environment)?.borrow()
             {
-                let result = l[0].call(&l[1..])?;
+                let args: Vec<Object> = l.iter().skip(1).cloned().collect();
+                let result = l[0].call(&args)?;
                 println!("Result: {}", printer::pr_str(&*result.borrow(), true));
                 result
             } else {
@@ -42,13 +43,20 @@ fn eval_ast(ast: Object, environment: &mut Environment) -> Result<Object, Box<dy
         )
         .into(),
         Form::Compound(Compound::Map(m)) => Form::map(
-            // XXX: this is really inefficient; I might want to look into
-            // immutable container libraries
             m.into_iter()
                 .map(|(k, v)| Ok((k.clone(), eval(v.clone(), environment)?)))
                 .collect::<Result<_, Box<dyn Error>>>()?,
         )
         .into(),
+        Form::Compound(Compound::Set(s)) => Form::set(
+            s.into_iter()
+                .map(|a| match &*eval(Form::Atom(a.clone()).into(), environment)?.borrow() {
+                    Form::Atom(a) => Ok(a.clone()),
+                    Form::Compound(_) => Err("Set elements must evaluate to atoms".into()),
+                })
+                .collect::<Result<_, Box<dyn Error>>>()?,
+        )
+        .into(),
         _ => ast.clone(),
     })
 }
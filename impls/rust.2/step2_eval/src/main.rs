@@ -4,7 +4,7 @@ use std::error::Error;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
-use libmal::Object;
+use libmal::{read_input, Object};
 
 mod builtins;
 mod eval;
@@ -15,6 +15,7 @@ type Environment = HashMap<String, Object>;
 
 fn main() {
     let mut rl = Editor::<()>::new();
+    let raw = std::env::args().nth(1).as_deref() == Some("--raw");
 
     let mut environment: HashMap<String, Object> = vec![
         ("+", builtins::binary_operation(|x, y| x + y)),
@@ -27,10 +28,9 @@ fn main() {
     .collect();
 
     loop {
-        let readline = rl.readline("user> ");
+        let readline = read_input(&mut rl, raw);
         match readline {
             Ok(line) => {
-                rl.add_history_entry(&line);
                 rep(line, &mut environment);
             }
             Err(ReadlineError::Interrupted) => std::process::exit(1),
@@ -1,6 +1,8 @@
+use std::cmp::Ordering;
 use std::fs::File;
 use std::io::Read;
 use std::rc::Rc;
+use std::sync::atomic::{self, AtomicUsize};
 use std::sync::Mutex;
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -8,20 +10,21 @@ extern crate rustyline;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
-use crate::printer::pr_seq;
-use crate::reader::read_str;
-use crate::types::MalErr::ErrMalVal;
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
-use crate::types::{MalArgs, MalRet, MalVal, _assoc, _dissoc, atom, error, func, hash_map};
+use itertools::Itertools;
 
-macro_rules! fn_t_int_int {
-    ($ret:ident, $fn:expr) => {{
-        |a: MalArgs| match (a[0].clone(), a[1].clone()) {
-            (Int(a0), Int(a1)) => Ok($ret($fn(a0, a1))),
-            _ => error("expecting (int,int) args"),
-        }
-    }};
-}
+use crate::printer::pr_seq;
+use crate::reader::{read_all, read_number, read_str, register_tag};
+use crate::types::Arity::{AtLeast, Between, Exactly};
+use crate::types::MalErr::{ErrMalVal, ErrString};
+use crate::types::MalVal::{
+    Atom, Bool, Char, Exc, Float, Func, Hash, Int, Keyword, LazySeq, List, MalFunc, Nil, Regex,
+    Set, Str, Sym, Vector,
+};
+use crate::types::{
+    _assoc, _dissoc, atom, error, ex_info, func, hash_map, hash_set, keyword, kind_error,
+    lazy_cons, lazy_seq, re_pattern, special, stack_trace, sym, Arity, MalArgs, MalErr, MalMap,
+    MalRet, MalSeq, MalSet, MalVal,
+};
 
 macro_rules! fn_is_type {
   ($($ps:pat),*) => {{
@@ -44,13 +47,218 @@ macro_rules! fn_str {
     }};
 }
 
+// Int op Int is overflow checked and stays an Int; if either side is a Float
+// both are promoted.
+fn num_op(
+    a: &MalVal,
+    b: &MalVal,
+    iop: fn(i64, i64) -> Option<i64>,
+    fop: fn(f64, f64) -> f64,
+) -> MalRet {
+    match (a, b) {
+        (Int(i), Int(j)) => match iop(*i, *j) {
+            Some(r) => Ok(Int(r)),
+            None => kind_error("arithmetic", "integer overflow"),
+        },
+        (Int(i), Float(y)) => Ok(Float(fop(*i as f64, *y))),
+        (Float(x), Int(j)) => Ok(Float(fop(*x, *j as f64))),
+        (Float(x), Float(y)) => Ok(Float(fop(*x, *y))),
+        _ => error("expecting (number,number) args"),
+    }
+}
+
+fn div_op(
+    a: &MalVal,
+    b: &MalVal,
+    iop: fn(i64, i64) -> Option<i64>,
+    fop: fn(f64, f64) -> f64,
+) -> MalRet {
+    match (a, b) {
+        (Int(_), Int(0)) => kind_error("arithmetic", "division by zero"),
+        _ => num_op(a, b, iop, fop),
+    }
+}
+
+fn num_cmp(a: &MalVal, b: &MalVal) -> Result<Option<Ordering>, MalErr> {
+    match (a, b) {
+        (Int(i), Int(j)) => Ok(Some(i.cmp(j))),
+        (Int(i), Float(y)) => Ok((*i as f64).partial_cmp(y)),
+        (Float(x), Int(j)) => Ok(x.partial_cmp(&(*j as f64))),
+        (Float(x), Float(y)) => Ok(x.partial_cmp(y)),
+        _ => Err(ErrString("expecting (number,number) args".to_string())),
+    }
+}
+
+fn add(a: MalArgs) -> MalRet {
+    a.iter().try_fold(Int(0), |acc, x| {
+        num_op(&acc, x, i64::checked_add, |x, y| x + y)
+    })
+}
+
+fn mul(a: MalArgs) -> MalRet {
+    a.iter().try_fold(Int(1), |acc, x| {
+        num_op(&acc, x, i64::checked_mul, |x, y| x * y)
+    })
+}
+
+// (- x) negates and (/ x) is the reciprocal, otherwise both fold left.
+fn sub(a: MalArgs) -> MalRet {
+    match a.len() {
+        1 => num_op(&Int(0), &a[0], i64::checked_sub, |x, y| x - y),
+        _ => a[1..].iter().try_fold(a[0].clone(), |acc, x| {
+            num_op(&acc, x, i64::checked_sub, |x, y| x - y)
+        }),
+    }
+}
+
+fn div(a: MalArgs) -> MalRet {
+    match a.len() {
+        1 => div_op(&Int(1), &a[0], i64::checked_div, |x, y| x / y),
+        _ => a[1..].iter().try_fold(a[0].clone(), |acc, x| {
+            div_op(&acc, x, i64::checked_div, |x, y| x / y)
+        }),
+    }
+}
+
+// True when every adjacent pair of args satisfies the ordering test.
+fn num_chain(a: MalArgs, test: fn(Ordering) -> bool) -> MalRet {
+    num_cmp(&a[0], &a[0])?;
+    for w in a.windows(2) {
+        match num_cmp(&w[0], &w[1])? {
+            Some(o) if test(o) => (),
+            _ => return Ok(Bool(false)),
+        }
+    }
+    Ok(Bool(true))
+}
+
+// Returns the arg that wins every comparison, keeping its original type.
+// A NaN anywhere wins, whatever its position.
+fn num_pick(a: MalArgs, wins: Ordering) -> MalRet {
+    let mut best = &a[0];
+    num_cmp(best, best)?;
+    for x in a[1..].iter() {
+        match num_cmp(x, best)? {
+            Some(o) if o == wins => best = x,
+            None if !matches!(best, Float(f) if f.is_nan()) => best = x,
+            _ => (),
+        }
+    }
+    Ok(best.clone())
+}
+
+fn quot(a: MalArgs) -> MalRet {
+    div_op(&a[0], &a[1], i64::checked_div, |x, y| (x / y).trunc())
+}
+
+fn rem(a: MalArgs) -> MalRet {
+    div_op(&a[0], &a[1], i64::checked_rem, |x, y| x % y)
+}
+
+// Unlike rem, the result of mod takes the sign of the divisor.
+fn modulo(a: MalArgs) -> MalRet {
+    div_op(
+        &a[0],
+        &a[1],
+        |i, j| {
+            i.checked_rem(j).map(|r| {
+                if r != 0 && (r < 0) != (j < 0) {
+                    r + j
+                } else {
+                    r
+                }
+            })
+        },
+        |x, y| {
+            let r = x % y;
+            if r != 0.0 && (r < 0.0) != (y < 0.0) {
+                r + y
+            } else {
+                r
+            }
+        },
+    )
+}
+
+fn abs(a: MalArgs) -> MalRet {
+    match a[0] {
+        Int(i) => match i.checked_abs() {
+            Some(r) => Ok(Int(r)),
+            None => kind_error("arithmetic", "integer overflow"),
+        },
+        Float(x) => Ok(Float(x.abs())),
+        _ => error("abs: expecting number arg"),
+    }
+}
+
 fn symbol(a: MalArgs) -> MalRet {
     match a[0] {
-        Str(ref s) => Ok(Sym(s.to_string())),
+        Str(ref s) => Ok(sym(s)),
+        Keyword(ref k) => Ok(sym(k)),
         _ => error("illegal symbol call"),
     }
 }
 
+// (keyword name) or (keyword ns name) for the namespaced keyword :ns/name
+fn keyword_fn(a: MalArgs) -> MalRet {
+    match (&a[0], a.get(1)) {
+        (_, None) => a[0].keyword(),
+        (Str(ns), Some(Str(name))) => Ok(keyword(&format!("{}/{}", ns, name))),
+        (Nil, Some(Str(name))) => Ok(keyword(name)),
+        _ => error("keyword: expecting string args"),
+    }
+}
+
+// Namespace and name of a keyword or symbol, split at the first / that is
+// not the whole name
+fn split_name(name: &str) -> (Option<&str>, &str) {
+    match name.find('/') {
+        Some(i) if name != "/" => (Some(&name[..i]), &name[i + 1..]),
+        _ => (None, name),
+    }
+}
+
+fn name(a: MalArgs) -> MalRet {
+    match a[0] {
+        Keyword(ref s) => Ok(Str(split_name(s).1.to_string())),
+        Sym(ref s) => Ok(Str(split_name(s).1.to_string())),
+        Str(_) => Ok(a[0].clone()),
+        _ => error(&format!("name: {} has no name", a[0].pr_str(true))),
+    }
+}
+
+fn namespace(a: MalArgs) -> MalRet {
+    let ns = match a[0] {
+        Keyword(ref s) => split_name(s).0,
+        Sym(ref s) => split_name(s).0,
+        _ => {
+            return error(&format!(
+                "namespace: {} has no namespace",
+                a[0].pr_str(true)
+            ))
+        }
+    };
+    Ok(ns.map_or(Nil, |ns| Str(ns.to_string())))
+}
+
+// An optional second arg names the source so read forms carry positions
+fn read_string(a: MalArgs) -> MalRet {
+    match (&a[0], a.get(1)) {
+        (Str(s), None) => read_str(s.to_string(), None),
+        (Str(s), Some(Str(f))) => read_str(s.to_string(), Some(f)),
+        _ => error("read-string: expecting (str) or (str,str) args"),
+    }
+}
+
+// Every form in the string, as a list
+fn read_all_string(a: MalArgs) -> MalRet {
+    match (&a[0], a.get(1)) {
+        (Str(s), None) => Ok(list!(read_all(s.to_string(), None)?)),
+        (Str(s), Some(Str(f))) => Ok(list!(read_all(s.to_string(), Some(f))?)),
+        _ => error("read-all-string: expecting (str) or (str,str) args"),
+    }
+}
+
 fn readline(a: MalArgs) -> MalRet {
     lazy_static! {
         static ref RL: Mutex<Editor<()>> = Mutex::new(Editor::<()>::new());
@@ -87,6 +295,261 @@ fn slurp(f: String) -> MalRet {
     }
 }
 
+// String functions count in characters rather than bytes
+fn str_arg<'a>(name: &str, a: &'a MalVal) -> Result<&'a str, MalErr> {
+    match a {
+        Str(ref s) => Ok(s),
+        _ => Err(ErrString(format!(
+            "{}: expecting string arg, got {}",
+            name,
+            a.pr_str(true)
+        ))),
+    }
+}
+
+// Byte offset of the nth character, s.len() for n == character count
+fn char_offset(s: &str, n: i64) -> Option<usize> {
+    if n < 0 {
+        return None;
+    }
+    s.char_indices()
+        .map(|(i, _)| i)
+        .chain(Some(s.len()))
+        .nth(n as usize)
+}
+
+fn subs(a: MalArgs) -> MalRet {
+    let s = str_arg("subs", &a[0])?;
+    let start = match a[1] {
+        Int(i) => char_offset(s, i),
+        _ => return error("subs: expecting int start"),
+    };
+    let end = match a.get(2) {
+        Some(Int(i)) => char_offset(s, *i),
+        Some(_) => return error("subs: expecting int end"),
+        None => Some(s.len()),
+    };
+    match (start, end) {
+        (Some(start), Some(end)) if start <= end => Ok(Str(s[start..end].to_string())),
+        _ => kind_error("index-out-of-bounds", "subs: index out of range"),
+    }
+}
+
+// Like Clojure, trailing empty strings are dropped and an empty separator
+// splits into characters
+fn split(a: MalArgs) -> MalRet {
+    let s = str_arg("split", &a[0])?;
+    let sep = str_arg("split", &a[1])?;
+    if s.is_empty() {
+        return Ok(vector!(vec![Str(String::new())]));
+    }
+    let mut parts: Vec<MalVal> = if sep.is_empty() {
+        s.chars().map(|c| Str(c.to_string())).collect()
+    } else {
+        s.split(sep).map(|p| Str(p.to_string())).collect()
+    };
+    while parts.last() == Some(&Str(String::new())) {
+        parts.pop();
+    }
+    Ok(vector!(parts))
+}
+
+fn join(a: MalArgs) -> MalRet {
+    let (sep, coll) = match a.len() {
+        1 => ("", &a[0]),
+        _ => (str_arg("join", &a[0])?, &a[1]),
+    };
+    let items = coll.elements()?;
+    items.iter().try_for_each(|x| x.realize())?;
+    // as in Clojure, a nil element joins as an empty string
+    Ok(Str(items
+        .iter()
+        .map(|x| match x {
+            Nil => String::new(),
+            _ => x.pr_str(false),
+        })
+        .join(sep)))
+}
+
+// (index-of s sub) or (index-of s sub from), in characters
+fn index_of(a: MalArgs) -> MalRet {
+    let s = str_arg("index-of", &a[0])?;
+    let sub = str_arg("index-of", &a[1])?;
+    let from = match a.get(2) {
+        Some(Int(i)) => char_offset(s, (*i).max(0)).unwrap_or(s.len()),
+        Some(_) => return error("index-of: expecting int from"),
+        None => 0,
+    };
+    Ok(match s[from..].find(sub) {
+        Some(i) => Int(s[..from + i].chars().count() as i64),
+        None => Nil,
+    })
+}
+
+// With a regex, the replacement is a string that may refer to groups as $1
+// or ${name}, or a function called with each match as re-find returns it
+fn replace(a: MalArgs) -> MalRet {
+    let s = str_arg("replace", &a[0])?;
+    match (&a[1], &a[2]) {
+        (Regex(re), Str(to)) => Ok(Str(re.replace_all(s, &to[..]).into_owned())),
+        (Regex(re), f) => {
+            let mut res = String::new();
+            let mut last = 0;
+            for caps in re.captures_iter(s) {
+                let m = caps.get(0).unwrap();
+                res.push_str(&s[last..m.start()]);
+                res.push_str(&f.apply(vec![re_groups(&caps)])?.pr_str(false));
+                last = m.end();
+            }
+            res.push_str(&s[last..]);
+            Ok(Str(res))
+        }
+        _ => {
+            let from = str_arg("replace", &a[1])?;
+            let to = str_arg("replace", &a[2])?;
+            if from.is_empty() {
+                return error("replace: empty match string");
+            }
+            Ok(Str(s.replace(from, to)))
+        }
+    }
+}
+
+fn re_arg<'a>(name: &str, a: &'a MalVal) -> Result<&'a regex::Regex, MalErr> {
+    match a {
+        Regex(re) => Ok(re),
+        _ => Err(ErrString(format!(
+            "{}: expecting regex arg, got {}",
+            name,
+            a.pr_str(true)
+        ))),
+    }
+}
+
+// A match is the matched string, or when the regex has groups a vector of
+// it and each group, nil for groups that did not participate
+fn re_groups(caps: &regex::Captures) -> MalVal {
+    if caps.len() == 1 {
+        return Str(caps[0].to_string());
+    }
+    vector!(caps
+        .iter()
+        .map(|g| g.map_or(Nil, |m| Str(m.as_str().to_string())))
+        .collect())
+}
+
+fn re_find(a: MalArgs) -> MalRet {
+    let re = re_arg("re-find", &a[0])?;
+    let s = str_arg("re-find", &a[1])?;
+    Ok(re.captures(s).map_or(Nil, |caps| re_groups(&caps)))
+}
+
+// Like re-find, but only when the regex matches the whole string
+fn re_matches(a: MalArgs) -> MalRet {
+    let re = re_arg("re-matches", &a[0])?;
+    let s = str_arg("re-matches", &a[1])?;
+    let whole = match regex::Regex::new(&format!("^(?:{})$", re.as_str())) {
+        Ok(whole) => whole,
+        Err(e) => return kind_error("regex", &format!("invalid regex: {}", e)),
+    };
+    Ok(whole.captures(s).map_or(Nil, |caps| re_groups(&caps)))
+}
+
+fn re_seq(a: MalArgs) -> MalRet {
+    let re = re_arg("re-seq", &a[0])?;
+    let s = str_arg("re-seq", &a[1])?;
+    let matches: Vec<MalVal> = re.captures_iter(s).map(|caps| re_groups(&caps)).collect();
+    if matches.is_empty() {
+        Ok(Nil)
+    } else {
+        Ok(list!(matches))
+    }
+}
+
+fn str_pred(a: &MalArgs, name: &str, test: fn(&str, &str) -> bool) -> MalRet {
+    Ok(Bool(test(str_arg(name, &a[0])?, str_arg(name, &a[1])?)))
+}
+
+fn str_map(a: &MalArgs, name: &str, f: fn(&str) -> String) -> MalRet {
+    Ok(Str(f(str_arg(name, &a[0])?)))
+}
+
+fn char(a: MalArgs) -> MalRet {
+    match a[0] {
+        Char(_) => Ok(a[0].clone()),
+        Int(i) if i >= 0 && i <= u32::MAX as i64 => match std::char::from_u32(i as u32) {
+            Some(c) => Ok(Char(c)),
+            None => error(&format!("char: {} is not a valid code point", i)),
+        },
+        _ => error(&format!(
+            "char: {} is not a valid code point",
+            a[0].pr_str(true)
+        )),
+    }
+}
+
+// Code point of a character, or a number truncated toward zero
+fn int(a: MalArgs) -> MalRet {
+    match a[0] {
+        Char(c) => Ok(Int(c as i64)),
+        Int(_) => Ok(a[0].clone()),
+        // i64::MAX as f64 rounds up to 2^63, which is already out of range
+        Float(f) if f.trunc() >= i64::MIN as f64 && f.trunc() < i64::MAX as f64 => {
+            Ok(Int(f as i64))
+        }
+        Float(f) if f.is_finite() => kind_error("arithmetic", "integer overflow"),
+        _ => error(&format!("int: cannot convert {}", a[0].pr_str(true))),
+    }
+}
+
+fn radix_arg(name: &str, a: &MalArgs) -> Result<u32, MalErr> {
+    match a.get(1) {
+        None => Ok(10),
+        Some(Int(r)) if 2 <= *r && *r <= 36 => Ok(*r as u32),
+        Some(r) => Err(ErrString(format!(
+            "{}: radix must be 2 to 36, got {}",
+            name,
+            r.pr_str(true)
+        ))),
+    }
+}
+
+// Numbers read as the reader reads them, or as integers in the given radix;
+// nil if the string is not a number
+fn string_to_number(a: MalArgs) -> MalRet {
+    let s = str_arg("string->number", &a[0])?;
+    match radix_arg("string->number", &a)? {
+        10 => match read_number(s) {
+            Some(n) => n,
+            None => Ok(Nil),
+        },
+        radix => Ok(i64::from_str_radix(s, radix).map(Int).unwrap_or(Nil)),
+    }
+}
+
+fn number_to_string(a: MalArgs) -> MalRet {
+    match (&a[0], radix_arg("number->string", &a)?) {
+        (Int(_), 10) | (Float(_), 10) => Ok(Str(a[0].pr_str(false))),
+        (Int(i), radix) => {
+            let mut n = i.unsigned_abs();
+            let mut digits = vec![];
+            loop {
+                digits.push(std::char::from_digit((n % radix as u64) as u32, radix).unwrap());
+                n /= radix as u64;
+                if n == 0 {
+                    break;
+                }
+            }
+            if *i < 0 {
+                digits.push('-');
+            }
+            Ok(Str(digits.into_iter().rev().collect()))
+        }
+        (Float(_), _) => error("number->string: radix is only supported for ints"),
+        _ => error("number->string: expecting number arg"),
+    }
+}
+
 fn time_ms(_a: MalArgs) -> MalRet {
     let ms_e = match SystemTime::now().duration_since(UNIX_EPOCH) {
         Ok(d) => d,
@@ -98,40 +561,159 @@ fn time_ms(_a: MalArgs) -> MalRet {
 }
 
 fn get(a: MalArgs) -> MalRet {
-    match (a[0].clone(), a[1].clone()) {
-        (Nil, _) => Ok(Nil),
-        (Hash(ref hm, _), Str(ref s)) => match hm.get(s) {
-            Some(mv) => Ok(mv.clone()),
-            None => Ok(Nil),
-        },
-        _ => error("illegal get args"),
+    Ok(lookup(&a[0], &a[1])?.unwrap_or(Nil))
+}
+
+// The value at k in a map, vector or set, None when it has none
+fn lookup(coll: &MalVal, k: &MalVal) -> Result<Option<MalVal>, MalErr> {
+    match (coll, k) {
+        (Nil, _) => Ok(None),
+        (Hash(hm, _), k) => Ok(hm.get(k).cloned()),
+        (Set(hs, _), k) => Ok(Some(k.clone()).filter(|k| hs.contains(k))),
+        (Vector(v, _), Int(i)) if *i >= 0 => Ok(v.get(*i as usize).cloned()),
+        (Vector(..), _) => Ok(None),
+        _ => Err(ErrString("illegal get args".to_string())),
+    }
+}
+
+fn get_in(a: MalArgs) -> MalRet {
+    let mut coll = a[0].clone();
+    for k in a[1].elements()?.iter() {
+        coll = match lookup(&coll, k)? {
+            Some(v) => v,
+            None => return Ok(a.get(2).cloned().unwrap_or(Nil)),
+        };
     }
+    Ok(coll)
 }
 
 fn assoc(a: MalArgs) -> MalRet {
     match a[0] {
-        Hash(ref hm, _) => _assoc((**hm).clone(), a[1..].to_vec()),
-        _ => error("assoc on non-Hash Map"),
+        Hash(ref hm, _) => _assoc(hm.clone(), a[1..].to_vec()),
+        Vector(ref v, _) => assoc_vector(v.clone(), &a[1..]),
+        Nil => hash_map(a[1..].to_vec()),
+        _ => error("assoc on non-Hash Map or Vector"),
+    }
+}
+
+// Replace elements of a vector by index. An index equal to the count
+// appends, as in Clojure.
+fn assoc_vector(mut v: MalSeq, kvs: &[MalVal]) -> MalRet {
+    if !kvs.len().is_multiple_of(2) {
+        return error("odd number of elements");
+    }
+    for (k, x) in kvs.iter().tuples() {
+        match *k {
+            Int(i) if i >= 0 && (i as usize) < v.len() => v = v.update(i as usize, x.clone()),
+            Int(i) if i >= 0 && i as usize == v.len() => v.push_back(x.clone()),
+            Int(_) => return kind_error("index-out-of-bounds", "assoc: index out of range"),
+            _ => return error("assoc: vector index must be an integer"),
+        }
     }
+    Ok(Vector(v, Rc::new(Nil)))
+}
+
+fn assoc_in(a: MalArgs) -> MalRet {
+    update_path(&a[0], &a[1].elements()?, &|_| Ok(a[2].clone()))
+}
+
+fn update(a: MalArgs) -> MalRet {
+    let old = get(vec![a[0].clone(), a[1].clone()])?;
+    let mut fargs = vec![old];
+    fargs.extend(a[3..].iter().cloned());
+    assoc(vec![a[0].clone(), a[1].clone(), a[2].apply(fargs)?])
+}
+
+fn update_in(a: MalArgs) -> MalRet {
+    update_path(&a[0], &a[1].elements()?, &|old| {
+        let mut fargs = vec![old];
+        fargs.extend(a[3..].iter().cloned());
+        a[2].apply(fargs)
+    })
+}
+
+// Replace the value at the end of a path of keys, creating maps for
+// missing levels
+fn update_path(coll: &MalVal, path: &MalSeq, f: &dyn Fn(MalVal) -> MalRet) -> MalRet {
+    let k = match path.front() {
+        Some(k) => k,
+        None => return error("empty key path"),
+    };
+    let old = lookup(coll, k)?.unwrap_or(Nil);
+    let new = if path.len() == 1 {
+        f(old)?
+    } else {
+        update_path(&old, &path.skip(1), f)?
+    };
+    assoc(vec![coll.clone(), k.clone(), new])
+}
+
+fn merge(a: MalArgs) -> MalRet {
+    let mut maps = a.iter().filter(|m| **m != Nil);
+    let (mut hm, meta) = match maps.next() {
+        Some(Hash(hm, meta)) => (hm.clone(), meta.clone()),
+        Some(_) => return error("merge: expecting map args"),
+        None => return Ok(Nil),
+    };
+    for m in maps {
+        match m {
+            Hash(other, _) => hm.extend(other.clone()),
+            _ => return error("merge: expecting map args"),
+        }
+    }
+    Ok(Hash(hm, meta))
 }
 
 fn dissoc(a: MalArgs) -> MalRet {
     match a[0] {
-        Hash(ref hm, _) => _dissoc((**hm).clone(), a[1..].to_vec()),
+        Hash(ref hm, _) => _dissoc(hm.clone(), a[1..].to_vec()),
         _ => error("dissoc on non-Hash Map"),
     }
 }
 
+// Whether k is a key of a map, a member of a set or an index of a vector
+// or string. Other collections have no keys to look up.
 fn contains_q(a: MalArgs) -> MalRet {
-    match (a[0].clone(), a[1].clone()) {
-        (Hash(ref hm, _), Str(ref s)) => Ok(Bool(hm.contains_key(s))),
-        _ => error("illegal get args"),
+    let in_range = |i: i64, len: usize| i >= 0 && (i as u64) < len as u64;
+    match (&a[0], &a[1]) {
+        (Hash(hm, _), k) => Ok(Bool(hm.contains_key(k))),
+        (Set(hs, _), k) => Ok(Bool(hs.contains(k))),
+        (Vector(v, _), &Int(i)) => Ok(Bool(in_range(i, v.len()))),
+        (Str(s), &Int(i)) => Ok(Bool(in_range(i, s.chars().count()))),
+        (Vector(..), _) | (Str(_), _) | (Nil, _) => Ok(Bool(false)),
+        (coll, _) => kind_error(
+            "illegal-argument",
+            &format!("contains? not supported on {}", type_name(coll)),
+        ),
+    }
+}
+
+// What kind of value v is, for errors that should not print it
+fn type_name(v: &MalVal) -> &'static str {
+    match v {
+        Nil => "nil",
+        Bool(_) => "boolean",
+        Int(_) => "integer",
+        Float(_) => "float",
+        Str(_) => "string",
+        Char(_) => "character",
+        Keyword(_) => "keyword",
+        Sym(_) => "symbol",
+        List(..) => "list",
+        Vector(..) => "vector",
+        Hash(..) => "map",
+        Set(..) => "set",
+        LazySeq(_) => "lazy seq",
+        Func(..) | MalFunc { .. } => "function",
+        Atom(_) => "atom",
+        Exc { .. } => "exception",
+        Regex(_) => "regex",
     }
 }
 
 fn keys(a: MalArgs) -> MalRet {
     match a[0] {
-        Hash(ref hm, _) => Ok(list!(hm.keys().map(|k| { Str(k.to_string()) }).collect())),
+        Hash(ref hm, _) => Ok(list!(hm.keys().cloned().collect())),
         _ => error("keys requires Hash Map"),
     }
 }
@@ -144,208 +726,983 @@ fn vals(a: MalArgs) -> MalRet {
 }
 
 fn vec(a: MalArgs) -> MalRet {
-    match a[0] {
-        List(ref v, _) | Vector(ref v, _) => Ok(vector!(v.to_vec())),
-        _ => error("non-seq passed to vec"),
-    }
+    Ok(Vector(a[0].elements()?, Rc::new(Nil)))
 }
 
 fn cons(a: MalArgs) -> MalRet {
     match a[1].clone() {
-        List(v, _) | Vector(v, _) => {
-            let mut new_v = vec![a[0].clone()];
-            new_v.extend_from_slice(&v);
-            Ok(list!(new_v.to_vec()))
+        List(mut v, _) | Vector(mut v, _) => {
+            v.push_front(a[0].clone());
+            Ok(List(v, Rc::new(Nil)))
+        }
+        LazySeq(_) => Ok(lazy_cons(a[0].clone(), a[1].clone())),
+        ref coll => {
+            let mut v = coll.elements()?;
+            v.push_front(a[0].clone());
+            Ok(List(v, Rc::new(Nil)))
         }
-        _ => error("cons expects seq as second arg"),
     }
 }
 
 fn concat(a: MalArgs) -> MalRet {
-    let mut new_v = vec![];
+    if a.iter().any(|seq| matches!(seq, LazySeq(_))) {
+        return Ok(lazy_concat(a.into_iter().collect()));
+    }
+    let mut new_v = MalSeq::new();
     for seq in a.iter() {
-        match seq {
-            List(v, _) | Vector(v, _) => new_v.extend_from_slice(v),
-            _ => return error("non-seq passed to concat"),
-        }
+        new_v.append(seq.elements()?);
     }
-    Ok(list!(new_v.to_vec()))
+    Ok(List(new_v, Rc::new(Nil)))
+}
+
+fn lazy_concat(seqs: MalSeq) -> MalVal {
+    lazy_seq(move || {
+        let mut seqs = seqs.clone();
+        while let Some(seq) = seqs.pop_front() {
+            if let Some((x, rest)) = seq.uncons()? {
+                seqs.push_front(rest);
+                return Ok(lazy_cons(x, lazy_concat(seqs)));
+            }
+        }
+        Ok(Nil)
+    })
 }
 
 fn nth(a: MalArgs) -> MalRet {
     match (a[0].clone(), a[1].clone()) {
         (List(seq, _), Int(idx)) | (Vector(seq, _), Int(idx)) => {
             if seq.len() <= idx as usize {
-                return error("nth: index out of range");
+                return kind_error("index-out-of-bounds", "nth: index out of range");
             }
             Ok(seq[idx as usize].clone())
         }
+        (ref seq @ LazySeq(_), Int(idx)) if idx >= 0 => match seq.iter().nth(idx as usize) {
+            Some(x) => x,
+            None => kind_error("index-out-of-bounds", "nth: index out of range"),
+        },
+        (LazySeq(_), Int(_)) => kind_error("index-out-of-bounds", "nth: index out of range"),
         _ => error("invalid args to nth"),
     }
 }
 
 fn first(a: MalArgs) -> MalRet {
-    match a[0].clone() {
-        List(ref seq, _) | Vector(ref seq, _) if seq.len() == 0 => Ok(Nil),
-        List(ref seq, _) | Vector(ref seq, _) => Ok(seq[0].clone()),
-        Nil => Ok(Nil),
-        _ => error("invalid args to first"),
-    }
+    Ok(a[0].uncons()?.map_or(Nil, |(first, _)| first))
 }
 
 fn rest(a: MalArgs) -> MalRet {
-    match a[0].clone() {
-        List(ref seq, _) | Vector(ref seq, _) => {
-            if seq.len() > 1 {
-                Ok(list!(seq[1..].to_vec()))
-            } else {
-                Ok(list![])
+    Ok(a[0].uncons()?.map_or(list![], |(_, rest)| rest))
+}
+
+fn apply(a: MalArgs) -> MalRet {
+    let f = &a[0];
+    let mut fargs = a[1..a.len() - 1].to_vec();
+    fargs.extend(a[a.len() - 1].elements()?);
+    f.apply(fargs)
+}
+
+fn truthy(v: &MalVal) -> bool {
+    !matches!(v, Nil | Bool(false))
+}
+
+// map, filter, remove, take-while and drop-while are lazy over lazy seqs;
+// over other collections they run eagerly so errors raised by f surface at
+// the call
+fn map(a: MalArgs) -> MalRet {
+    match a[1] {
+        LazySeq(_) => Ok(lazy_map(a[0].clone(), a[1].clone())),
+        ref coll => {
+            let mut res = vec![];
+            for mv in coll.elements()?.iter() {
+                res.push(a[0].apply(vec![mv.clone()])?)
             }
+            Ok(list!(res))
         }
-        Nil => Ok(list![]),
-        _ => error("invalid args to first"),
     }
 }
 
-fn apply(a: MalArgs) -> MalRet {
-    match a[a.len() - 1] {
-        List(ref v, _) | Vector(ref v, _) => {
-            let f = &a[0];
-            let mut fargs = a[1..a.len() - 1].to_vec();
-            fargs.extend_from_slice(&v);
-            f.apply(fargs)
+fn lazy_map(f: MalVal, coll: MalVal) -> MalVal {
+    lazy_seq(move || match coll.uncons()? {
+        Some((x, rest)) => Ok(lazy_cons(f.apply(vec![x])?, lazy_map(f.clone(), rest))),
+        None => Ok(Nil),
+    })
+}
+
+fn filter(a: MalArgs) -> MalRet {
+    filter_by(&a, true)
+}
+
+fn remove(a: MalArgs) -> MalRet {
+    filter_by(&a, false)
+}
+
+// Keep the elements for which f's result is as truthy as keep
+fn filter_by(a: &MalArgs, keep: bool) -> MalRet {
+    match a[1] {
+        LazySeq(_) => Ok(lazy_filter(a[0].clone(), a[1].clone(), keep)),
+        ref coll => {
+            let mut res = vec![];
+            for mv in coll.elements()?.iter() {
+                if truthy(&a[0].apply(vec![mv.clone()])?) == keep {
+                    res.push(mv.clone());
+                }
+            }
+            Ok(list!(res))
         }
-        _ => error("apply called with non-seq"),
     }
 }
 
-fn map(a: MalArgs) -> MalRet {
+fn lazy_filter(f: MalVal, coll: MalVal, keep: bool) -> MalVal {
+    lazy_seq(move || {
+        let mut seq = coll.clone();
+        while let Some((x, rest)) = seq.uncons()? {
+            if truthy(&f.apply(vec![x.clone()])?) == keep {
+                return Ok(lazy_cons(x, lazy_filter(f.clone(), rest, keep)));
+            }
+            seq = rest;
+        }
+        Ok(Nil)
+    })
+}
+
+fn take_while(a: MalArgs) -> MalRet {
     match a[1] {
-        List(ref v, _) | Vector(ref v, _) => {
+        LazySeq(_) => Ok(lazy_take_while(a[0].clone(), a[1].clone())),
+        ref coll => {
             let mut res = vec![];
-            for mv in v.iter() {
-                res.push(a[0].apply(vec![mv.clone()])?)
+            for mv in coll.elements()?.iter() {
+                if !truthy(&a[0].apply(vec![mv.clone()])?) {
+                    break;
+                }
+                res.push(mv.clone());
             }
             Ok(list!(res))
         }
-        _ => error("map called with non-seq"),
     }
 }
 
+fn lazy_take_while(f: MalVal, coll: MalVal) -> MalVal {
+    lazy_seq(move || match coll.uncons()? {
+        Some((x, rest)) if truthy(&f.apply(vec![x.clone()])?) => {
+            Ok(lazy_cons(x, lazy_take_while(f.clone(), rest)))
+        }
+        _ => Ok(Nil),
+    })
+}
+
+fn drop_while(a: MalArgs) -> MalRet {
+    let (f, coll) = (a[0].clone(), a[1].clone());
+    let skip = move || {
+        let mut seq = coll.clone();
+        while let Some((x, rest)) = seq.uncons()? {
+            if !truthy(&f.apply(vec![x.clone()])?) {
+                return Ok(lazy_cons(x, rest));
+            }
+            seq = rest;
+        }
+        Ok(Nil)
+    };
+    match a[1] {
+        LazySeq(_) => Ok(lazy_seq(skip)),
+        _ => Ok(List(skip()?.elements()?, Rc::new(Nil))),
+    }
+}
+
+fn take(a: MalArgs) -> MalRet {
+    match a[0] {
+        Int(n) => Ok(lazy_take(n, a[1].clone())),
+        _ => error("take: expecting (int,seq) args"),
+    }
+}
+
+fn lazy_take(n: i64, coll: MalVal) -> MalVal {
+    lazy_seq(move || {
+        if n <= 0 {
+            return Ok(Nil);
+        }
+        match coll.uncons()? {
+            Some((x, rest)) => Ok(lazy_cons(x, lazy_take(n - 1, rest))),
+            None => Ok(Nil),
+        }
+    })
+}
+
+fn drop(a: MalArgs) -> MalRet {
+    let (n, coll) = match a[0] {
+        Int(n) => (n, a[1].clone()),
+        _ => return error("drop: expecting (int,seq) args"),
+    };
+    Ok(lazy_seq(move || {
+        let mut seq = coll.clone();
+        for _ in 0..n {
+            match seq.uncons()? {
+                Some((_, rest)) => seq = rest,
+                None => return Ok(Nil),
+            }
+        }
+        Ok(seq)
+    }))
+}
+
+fn range(a: MalArgs) -> MalRet {
+    if a.iter().any(|x| !matches!(x, Int(_) | Float(_))) {
+        return error("range: expecting number args");
+    }
+    Ok(match a.len() {
+        0 => lazy_range(Int(0), None, Int(1)),
+        1 => lazy_range(Int(0), Some(a[0].clone()), Int(1)),
+        2 => lazy_range(a[0].clone(), Some(a[1].clone()), Int(1)),
+        _ => lazy_range(a[0].clone(), Some(a[1].clone()), a[2].clone()),
+    })
+}
+
+// Counts from start by step up to (or, with a negative step, down to) end,
+// forever when there is no end
+fn lazy_range(start: MalVal, end: Option<MalVal>, step: MalVal) -> MalVal {
+    lazy_seq(move || {
+        if let Some(ref end) = end {
+            let done = match num_cmp(&step, &Int(0))? {
+                Some(Ordering::Less) => num_cmp(&start, end)? != Some(Ordering::Greater),
+                _ => num_cmp(&start, end)? != Some(Ordering::Less),
+            };
+            if done {
+                return Ok(Nil);
+            }
+        }
+        let next = add(vec![start.clone(), step.clone()])?;
+        Ok(lazy_cons(
+            start.clone(),
+            lazy_range(next, end.clone(), step.clone()),
+        ))
+    })
+}
+
+fn iterate(f: MalVal, x: MalVal) -> MalVal {
+    let next = x.clone();
+    lazy_cons(
+        x,
+        lazy_seq(move || Ok(iterate(f.clone(), f.apply(vec![next.clone()])?))),
+    )
+}
+
+fn reduce(a: MalArgs) -> MalRet {
+    let f = &a[0];
+    let mut items = a[a.len() - 1].iter();
+    let mut acc = match a.len() {
+        3 => a[1].clone(),
+        _ => match items.next() {
+            Some(x) => x?,
+            None => return f.apply(vec![]),
+        },
+    };
+    for x in items {
+        acc = f.apply(vec![acc, x?])?;
+    }
+    Ok(acc)
+}
+
+// The first truthy result of f on an element, nil when there is none
+fn some(a: MalArgs) -> MalRet {
+    for x in a[1].iter() {
+        let res = a[0].apply(vec![x?])?;
+        if truthy(&res) {
+            return Ok(res);
+        }
+    }
+    Ok(Nil)
+}
+
+fn every_q(a: MalArgs) -> MalRet {
+    for x in a[1].iter() {
+        if !truthy(&a[0].apply(vec![x?])?) {
+            return Ok(Bool(false));
+        }
+    }
+    Ok(Bool(true))
+}
+
+fn reverse(a: MalArgs) -> MalRet {
+    Ok(list!(a[0].elements()?.into_iter().rev().collect()))
+}
+
+// Natural order: numbers by value, strings, keywords and symbols
+// lexically, and lists and vectors element by element
+fn compare(a: &MalVal, b: &MalVal) -> Result<Ordering, MalErr> {
+    match (a, b) {
+        (Int(_), _) | (Float(_), _) if matches!(b, Int(_) | Float(_)) => {
+            Ok(num_cmp(a, b)?.unwrap_or(Ordering::Equal))
+        }
+        (Str(x), Str(y)) => Ok(x.cmp(y)),
+        (Sym(x), Sym(y)) => Ok(x.name().cmp(y.name())),
+        (Bool(x), Bool(y)) => Ok(x.cmp(y)),
+        (Char(x), Char(y)) => Ok(x.cmp(y)),
+        (Keyword(x), Keyword(y)) => Ok(x.cmp(y)),
+        (Nil, Nil) => Ok(Ordering::Equal),
+        (Nil, _) => Ok(Ordering::Less),
+        (_, Nil) => Ok(Ordering::Greater),
+        (List(x, _), List(y, _))
+        | (List(x, _), Vector(y, _))
+        | (Vector(x, _), List(y, _))
+        | (Vector(x, _), Vector(y, _)) => {
+            for (x, y) in x.iter().zip(y.iter()) {
+                match compare(x, y)? {
+                    Ordering::Equal => (),
+                    o => return Ok(o),
+                }
+            }
+            Ok(x.len().cmp(&y.len()))
+        }
+        _ => Err(ErrString(format!(
+            "cannot compare {} and {}",
+            a.pr_str(true),
+            b.pr_str(true)
+        ))),
+    }
+}
+
+// Order by a mal comparator, which returns a number like compare or, like <,
+// whether its first arg sorts before its second
+fn compare_with(f: &MalVal, a: &MalVal, b: &MalVal) -> Result<Ordering, MalErr> {
+    match f.apply(vec![a.clone(), b.clone()])? {
+        Int(n) => Ok(n.cmp(&0)),
+        Float(n) => Ok(n.partial_cmp(&0.0).unwrap_or(Ordering::Equal)),
+        res if truthy(&res) => Ok(Ordering::Less),
+        _ if truthy(&f.apply(vec![b.clone(), a.clone()])?) => Ok(Ordering::Greater),
+        _ => Ok(Ordering::Equal),
+    }
+}
+
+// Stable merge sort that stops at the first error from cmp. Unlike
+// slice::sort_by it tolerates comparators that are not a total order.
+fn merge_sort<T>(
+    mut v: Vec<T>,
+    cmp: &dyn Fn(&T, &T) -> Result<Ordering, MalErr>,
+) -> Result<Vec<T>, MalErr> {
+    if v.len() <= 1 {
+        return Ok(v);
+    }
+    let right = v.split_off(v.len() / 2);
+    let (left, right) = (merge_sort(v, cmp)?, merge_sort(right, cmp)?);
+    let mut out = Vec::with_capacity(left.len() + right.len());
+    let (mut left, mut right) = (left.into_iter().peekable(), right.into_iter().peekable());
+    while let (Some(x), Some(y)) = (left.peek(), right.peek()) {
+        let next = if cmp(y, x)? == Ordering::Less {
+            right.next()
+        } else {
+            left.next()
+        };
+        out.extend(next);
+    }
+    out.extend(left);
+    out.extend(right);
+    Ok(out)
+}
+
+fn sort(a: MalArgs) -> MalRet {
+    let items: Vec<MalVal> = a[a.len() - 1].elements()?.into_iter().collect();
+    let sorted = match a.len() {
+        2 => merge_sort(items, &|x, y| compare_with(&a[0], x, y))?,
+        _ => merge_sort(items, &compare)?,
+    };
+    Ok(list!(sorted))
+}
+
+fn sort_by(a: MalArgs) -> MalRet {
+    let mut keyed = vec![];
+    for x in a[a.len() - 1].iter() {
+        let x = x?;
+        keyed.push((a[0].apply(vec![x.clone()])?, x));
+    }
+    let sorted = match a.len() {
+        3 => merge_sort(keyed, &|x, y| compare_with(&a[1], &x.0, &y.0))?,
+        _ => merge_sort(keyed, &|x, y| compare(&x.0, &y.0))?,
+    };
+    Ok(list!(sorted.into_iter().map(|(_, x)| x).collect()))
+}
+
+fn group_by(a: MalArgs) -> MalRet {
+    let mut groups = MalMap::default();
+    for x in a[1].iter() {
+        let x = x?;
+        let k = a[0].apply(vec![x.clone()])?;
+        let mut group = match groups.get(&k) {
+            Some(Vector(v, _)) => v.clone(),
+            _ => MalSeq::new(),
+        };
+        group.push_back(x);
+        groups.insert(k, Vector(group, Rc::new(Nil)));
+    }
+    Ok(Hash(groups, Rc::new(Nil)))
+}
+
+fn frequencies(a: MalArgs) -> MalRet {
+    let mut counts = MalMap::default();
+    for x in a[0].iter() {
+        let x = x?;
+        let n = match counts.get(&x) {
+            Some(Int(n)) => *n,
+            _ => 0,
+        };
+        counts.insert(x, Int(n + 1));
+    }
+    Ok(Hash(counts, Rc::new(Nil)))
+}
+
+// (partition n coll), (partition n step coll) or (partition n step pad coll);
+// a short last partition is dropped unless pad can fill it
+fn partition(a: MalArgs) -> MalRet {
+    let (n, step) = match (&a[0], &a[if a.len() > 2 { 1 } else { 0 }]) {
+        (Int(n), Int(step)) if *n > 0 && *step > 0 => (*n as usize, *step as usize),
+        _ => return error("partition: expecting positive int size and step"),
+    };
+    let pad = if a.len() == 4 {
+        Some(a[2].elements()?)
+    } else {
+        None
+    };
+    let items = a[a.len() - 1].elements()?;
+    let mut parts = vec![];
+    let mut i = 0;
+    while i < items.len() {
+        let mut part = items.skip(i).take(n.min(items.len() - i));
+        if part.len() < n {
+            match pad {
+                Some(ref pad) => part.append(pad.take((n - part.len()).min(pad.len()))),
+                None => break,
+            }
+        }
+        parts.push(List(part, Rc::new(Nil)));
+        i += step;
+    }
+    Ok(list!(parts))
+}
+
+fn interleave(a: MalArgs) -> MalRet {
+    let mut seqs: Vec<_> = a.iter().map(|s| s.iter()).collect();
+    let mut res = vec![];
+    if seqs.is_empty() {
+        return Ok(list!(res));
+    }
+    'rounds: loop {
+        let mut round = vec![];
+        for seq in seqs.iter_mut() {
+            match seq.next() {
+                Some(x) => round.push(x?),
+                None => break 'rounds,
+            }
+        }
+        res.extend(round);
+    }
+    Ok(list!(res))
+}
+
+fn distinct(a: MalArgs) -> MalRet {
+    let mut seen = MalSet::default();
+    let mut res = vec![];
+    for x in a[0].iter() {
+        let x = x?;
+        if seen.insert(x.clone()).is_none() {
+            res.push(x);
+        }
+    }
+    Ok(list!(res))
+}
+
+fn last(a: MalArgs) -> MalRet {
+    let mut last = Nil;
+    for x in a[0].iter() {
+        last = x?;
+    }
+    Ok(last)
+}
+
+fn butlast(a: MalArgs) -> MalRet {
+    let items = a[0].elements()?;
+    match items.len() {
+        0 | 1 => Ok(Nil),
+        n => Ok(List(items.take(n - 1), Rc::new(Nil))),
+    }
+}
+
+fn zipmap(a: MalArgs) -> MalRet {
+    let mut hm = MalMap::default();
+    for (k, v) in a[0].iter().zip(a[1].iter()) {
+        hm.insert(k?, v?);
+    }
+    Ok(Hash(hm, Rc::new(Nil)))
+}
+
+fn into(a: MalArgs) -> MalRet {
+    let mut args = vec![a[0].clone()];
+    args.extend(a[1].elements()?);
+    conj(args)
+}
+
 fn conj(a: MalArgs) -> MalRet {
     match a[0] {
         List(ref v, _) => {
-            let sl = a[1..]
-                .iter()
-                .rev()
-                .map(|a| a.clone())
-                .collect::<Vec<MalVal>>();
-            Ok(list!([&sl[..], v].concat()))
-        }
-        Vector(ref v, _) => Ok(vector!([v, &a[1..]].concat())),
+            let mut v = v.clone();
+            for x in a[1..].iter() {
+                v.push_front(x.clone());
+            }
+            Ok(List(v, Rc::new(Nil)))
+        }
+        Vector(ref v, _) => {
+            let mut v = v.clone();
+            v.extend(a[1..].iter().cloned());
+            Ok(Vector(v, Rc::new(Nil)))
+        }
+        LazySeq(_) => Ok(a[1..]
+            .iter()
+            .fold(a[0].clone(), |seq, x| lazy_cons(x.clone(), seq))),
+        Set(ref hs, _) => {
+            let mut hs = hs.clone();
+            hs.extend(a[1..].iter().cloned());
+            Ok(Set(hs, Rc::new(Nil)))
+        }
+        // entries are [key value] vectors or maps
+        Hash(ref hm, _) => {
+            let mut hm = hm.clone();
+            for x in a[1..].iter() {
+                match x {
+                    Vector(kv, _) if kv.len() == 2 => {
+                        hm.insert(kv[0].clone(), kv[1].clone());
+                    }
+                    Hash(other, _) => hm.extend(other.clone()),
+                    _ => return error("conj: expecting [key value] entries for a map"),
+                }
+            }
+            Ok(Hash(hm, Rc::new(Nil)))
+        }
+        Nil => conj(
+            vec![list![]]
+                .into_iter()
+                .chain(a[1..].iter().cloned())
+                .collect(),
+        ),
         _ => error("conj: called with non-seq"),
     }
 }
 
+fn disj(a: MalArgs) -> MalRet {
+    match a[0] {
+        Set(ref hs, _) => {
+            let mut hs = hs.clone();
+            for x in a[1..].iter() {
+                hs.remove(x);
+            }
+            Ok(Set(hs, Rc::new(Nil)))
+        }
+        Nil => Ok(Nil),
+        _ => error("disj: called with non-set"),
+    }
+}
+
+fn set(a: MalArgs) -> MalRet {
+    match a[0] {
+        Set(ref hs, _) => Ok(Set(hs.clone(), Rc::new(Nil))),
+        _ => Ok(Set(a[0].elements()?.into_iter().collect(), Rc::new(Nil))),
+    }
+}
+
+fn set_args(name: &str, a: &MalArgs) -> Result<Vec<MalSet>, MalErr> {
+    a.iter()
+        .map(|x| match x {
+            Set(hs, _) => Ok(hs.clone()),
+            _ => Err(ErrString(format!("{}: expecting set args", name))),
+        })
+        .collect()
+}
+
+fn union(a: MalArgs) -> MalRet {
+    Ok(Set(MalSet::unions(set_args("union", &a)?), Rc::new(Nil)))
+}
+
+fn intersection(a: MalArgs) -> MalRet {
+    let mut sets = set_args("intersection", &a)?.into_iter();
+    let first = sets.next().unwrap_or_default();
+    Ok(Set(
+        sets.fold(first, |acc, hs| acc.intersection(hs)),
+        Rc::new(Nil),
+    ))
+}
+
+fn difference(a: MalArgs) -> MalRet {
+    let mut sets = set_args("difference", &a)?.into_iter();
+    let first = sets.next().unwrap_or_default();
+    Ok(Set(
+        sets.fold(first, |acc, hs| acc.relative_complement(hs)),
+        Rc::new(Nil),
+    ))
+}
+
 fn seq(a: MalArgs) -> MalRet {
     match a[0] {
         List(ref v, _) | Vector(ref v, _) if v.len() == 0 => Ok(Nil),
-        List(ref v, _) | Vector(ref v, _) => Ok(list!(v.to_vec())),
-        Str(ref s) if s.len() == 0 => Ok(Nil),
-        Str(ref s) if !a[0].keyword_q() => {
-            Ok(list!(s.chars().map(|c| { Str(c.to_string()) }).collect()))
-        }
+        List(ref v, _) | Vector(ref v, _) => Ok(List(v.clone(), Rc::new(Nil))),
+        LazySeq(_) if a[0].uncons()?.is_none() => Ok(Nil),
+        LazySeq(_) => Ok(a[0].clone()),
         Nil => Ok(Nil),
+        Hash(..) | Set(..) | Str(_) => {
+            let v = a[0].elements()?;
+            if v.is_empty() {
+                Ok(Nil)
+            } else {
+                Ok(List(v, Rc::new(Nil)))
+            }
+        }
         _ => error("seq: called with non-seq"),
     }
 }
 
+static GENSYM_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+fn gensym_with(prefix: &str) -> MalVal {
+    let n = GENSYM_COUNTER.fetch_add(1, atomic::Ordering::Relaxed);
+    sym(&format!("{}{}", prefix, n))
+}
+
+fn gensym(a: MalArgs) -> MalRet {
+    match a.first() {
+        None => Ok(gensym_with("G__")),
+        Some(Str(prefix)) => Ok(gensym_with(prefix)),
+        _ => error("gensym: expecting optional (str) arg"),
+    }
+}
+
+// Expand a binding vector whose patterns may be [a b & more :as all] or
+// {a :a :keys [b c] :strs [d] :as m} into plain symbol/expression pairs for let*
+fn destructure(a: MalArgs) -> MalRet {
+    match a[0] {
+        List(ref bs, _) | Vector(ref bs, _) if bs.len() % 2 == 0 => {
+            let mut out = vec![];
+            for (pat, expr) in bs.iter().tuples() {
+                bind_pattern(&mut out, pat, expr.clone())?;
+            }
+            Ok(vector!(out))
+        }
+        _ => error("destructure: expecting a binding vector with an even number of forms"),
+    }
+}
+
+fn bind_pattern(out: &mut MalArgs, pat: &MalVal, expr: MalVal) -> Result<(), MalErr> {
+    let call = |f: &str, arg: &MalVal| list![sym(f), arg.clone()];
+    match pat {
+        Sym(_) => {
+            out.push(pat.clone());
+            out.push(expr);
+        }
+        Vector(ps, _) => {
+            let whole = gensym_with("vec__");
+            out.push(whole.clone());
+            out.push(expr);
+            let mut seq = whole.clone();
+            let mut i = 0;
+            while i < ps.len() {
+                match (&ps[i], ps.get(i + 1)) {
+                    (Sym(special::AMP), Some(rest)) => {
+                        bind_pattern(out, rest, call("seq", &seq))?;
+                        i += 2;
+                    }
+                    (Keyword(k), Some(name @ Sym(_))) if &**k == "as" => {
+                        out.push(name.clone());
+                        out.push(whole.clone());
+                        i += 2;
+                    }
+                    (Sym(special::AMP), None) => {
+                        return Err(ErrString(
+                            "destructure: missing pattern after &".to_string(),
+                        ))
+                    }
+                    (p, _) => {
+                        bind_pattern(out, p, call("first", &seq))?;
+                        i += 1;
+                        if i < ps.len() {
+                            let next = gensym_with("seq__");
+                            out.push(next.clone());
+                            out.push(call("rest", &seq));
+                            seq = next;
+                        }
+                    }
+                }
+            }
+        }
+        Hash(hm, _) => {
+            let whole = gensym_with("map__");
+            out.push(whole.clone());
+            out.push(expr);
+            for (k, v) in hm.iter() {
+                match (k, v) {
+                    (Keyword(k), Sym(_)) if &**k == "as" => {
+                        out.push(v.clone());
+                        out.push(whole.clone());
+                    }
+                    (Keyword(k), Vector(syms, _)) if &**k == "keys" || &**k == "strs" => {
+                        for binding in syms.iter() {
+                            let name = match binding {
+                                Sym(name) => name,
+                                _ => {
+                                    return Err(ErrString(
+                                        "destructure: :keys and :strs take symbols".to_string(),
+                                    ))
+                                }
+                            };
+                            let key = if &**k == "keys" {
+                                keyword(name)
+                            } else {
+                                Str(name.to_string())
+                            };
+                            out.push(binding.clone());
+                            out.push(list![sym("get"), whole.clone(), key]);
+                        }
+                    }
+                    (Keyword(_), _) => {
+                        return Err(ErrString(format!(
+                            "destructure: unsupported map pattern entry {} {}",
+                            k.pr_str(true),
+                            v.pr_str(true)
+                        )))
+                    }
+                    // {pattern key}
+                    _ => {
+                        let lookup = list![sym("get"), whole.clone(), v.clone()];
+                        bind_pattern(out, k, lookup)?;
+                    }
+                }
+            }
+        }
+        _ => {
+            return Err(ErrString(format!(
+                "destructure: unsupported binding pattern {}",
+                pat.pr_str(true)
+            )))
+        }
+    }
+    Ok(())
+}
+
+fn ex_info_fn(a: MalArgs) -> MalRet {
+    match (&a[0], &a[1]) {
+        (Str(msg), data @ Hash(..)) => {
+            Ok(ex_info(msg, data.clone(), a.get(2).cloned().unwrap_or(Nil)))
+        }
+        _ => error("ex-info: expecting (str,map) or (str,map,cause) args"),
+    }
+}
+
+type Builtin = (&'static str, Arity, fn(MalArgs) -> MalRet);
+
 pub fn ns() -> Vec<(&'static str, MalVal)> {
-    vec![
-        ("=", func(|a| Ok(Bool(a[0] == a[1])))),
-        ("throw", func(|a| Err(ErrMalVal(a[0].clone())))),
-        ("nil?", func(fn_is_type!(Nil))),
-        ("true?", func(fn_is_type!(Bool(true)))),
-        ("false?", func(fn_is_type!(Bool(false)))),
-        ("symbol", func(symbol)),
-        ("symbol?", func(fn_is_type!(Sym(_)))),
-        (
-            "string?",
-            func(fn_is_type!(Str(ref s) if !s.starts_with("\u{29e}"))),
-        ),
-        ("keyword", func(|a| a[0].keyword())),
-        (
-            "keyword?",
-            func(fn_is_type!(Str(ref s) if s.starts_with("\u{29e}"))),
-        ),
-        ("number?", func(fn_is_type!(Int(_)))),
+    let builtins: Vec<Builtin> = vec![
+        ("=", Exactly(2), |a| Ok(Bool(a[0] == a[1]))),
+        ("throw", Exactly(1), |a| Err(ErrMalVal(a[0].clone()))),
+        ("stack-trace", Exactly(1), |a| Ok(stack_trace(&a[0]))),
+        ("ex-info", Between(2, 3), ex_info_fn),
+        ("gensym", Between(0, 1), gensym),
+        ("destructure", Exactly(1), destructure),
+        ("ex-data", Exactly(1), |a| match &a[0] {
+            Exc { data, .. } => Ok((**data).clone()),
+            _ => Ok(Nil),
+        }),
+        ("ex-message", Exactly(1), |a| match &a[0] {
+            Exc { msg, .. } => Ok(Str(msg.clone())),
+            _ => Ok(Nil),
+        }),
+        ("ex-cause", Exactly(1), |a| match &a[0] {
+            Exc { cause, .. } => Ok((**cause).clone()),
+            _ => Ok(Nil),
+        }),
+        ("nil?", Exactly(1), fn_is_type!(Nil)),
+        ("true?", Exactly(1), fn_is_type!(Bool(true))),
+        ("false?", Exactly(1), fn_is_type!(Bool(false))),
+        ("symbol", Exactly(1), symbol),
+        ("symbol?", Exactly(1), fn_is_type!(Sym(_))),
+        ("string?", Exactly(1), fn_is_type!(Str(_))),
+        ("keyword", Between(1, 2), keyword_fn),
+        ("keyword?", Exactly(1), fn_is_type!(Keyword(_))),
+        ("name", Exactly(1), name),
+        ("namespace", Exactly(1), namespace),
+        ("number?", Exactly(1), fn_is_type!(Int(_), Float(_))),
+        ("char?", Exactly(1), fn_is_type!(Char(_))),
         (
             "fn?",
-            func(fn_is_type!(MalFunc{is_macro,..} if !is_macro,Func(_,_))),
+            Exactly(1),
+            fn_is_type!(MalFunc{is_macro,..} if !is_macro,Func(..)),
         ),
         (
             "macro?",
-            func(fn_is_type!(MalFunc{is_macro,..} if is_macro)),
+            Exactly(1),
+            fn_is_type!(MalFunc{is_macro,..} if is_macro),
         ),
-        ("pr-str", func(|a| Ok(Str(pr_seq(&a, true, "", "", " "))))),
-        ("str", func(|a| Ok(Str(pr_seq(&a, false, "", "", ""))))),
-        (
-            "prn",
-            func(|a| {
-                println!("{}", pr_seq(&a, true, "", "", " "));
+        ("pr-str", AtLeast(0), |a| {
+            a.iter().try_for_each(|x| x.realize())?;
+            Ok(Str(pr_seq(&a, true, "", "", " ")))
+        }),
+        ("str", AtLeast(0), |a| {
+            a.iter().try_for_each(|x| x.realize())?;
+            Ok(Str(pr_seq(&a, false, "", "", "")))
+        }),
+        ("prn", AtLeast(0), |a| {
+            a.iter().try_for_each(|x| x.realize())?;
+            println!("{}", pr_seq(&a, true, "", "", " "));
+            Ok(Nil)
+        }),
+        ("println", AtLeast(0), |a| {
+            a.iter().try_for_each(|x| x.realize())?;
+            println!("{}", pr_seq(&a, false, "", "", " "));
+            Ok(Nil)
+        }),
+        ("read-string", Between(1, 2), read_string),
+        ("read-all-string", Between(1, 2), read_all_string),
+        ("register-tag!", Exactly(2), |a| match a[0] {
+            Sym(ref s) => {
+                register_tag(s.clone(), a[1].clone());
                 Ok(Nil)
-            }),
-        ),
+            }
+            _ => error("register-tag!: expecting a symbol tag"),
+        }),
+        ("subs", Between(2, 3), subs),
+        ("split", Exactly(2), split),
+        ("join", Between(1, 2), join),
+        ("trim", Exactly(1), |a| {
+            str_map(&a, "trim", |s| s.trim().to_string())
+        }),
+        ("triml", Exactly(1), |a| {
+            str_map(&a, "triml", |s| s.trim_start().to_string())
+        }),
+        ("trimr", Exactly(1), |a| {
+            str_map(&a, "trimr", |s| s.trim_end().to_string())
+        }),
+        ("upper-case", Exactly(1), |a| {
+            str_map(&a, "upper-case", str::to_uppercase)
+        }),
+        ("lower-case", Exactly(1), |a| {
+            str_map(&a, "lower-case", str::to_lowercase)
+        }),
+        ("starts-with?", Exactly(2), |a| {
+            str_pred(&a, "starts-with?", |s, t| s.starts_with(t))
+        }),
+        ("ends-with?", Exactly(2), |a| {
+            str_pred(&a, "ends-with?", |s, t| s.ends_with(t))
+        }),
+        ("includes?", Exactly(2), |a| {
+            str_pred(&a, "includes?", |s, t| s.contains(t))
+        }),
+        ("index-of", Between(2, 3), index_of),
+        ("replace", Exactly(3), replace),
+        ("re-pattern", Exactly(1), |a| match a[0] {
+            Regex(_) => Ok(a[0].clone()),
+            _ => re_pattern(str_arg("re-pattern", &a[0])?),
+        }),
+        ("re-find", Exactly(2), re_find),
+        ("re-matches", Exactly(2), re_matches),
+        ("re-seq", Exactly(2), re_seq),
+        ("char", Exactly(1), char),
+        ("int", Exactly(1), int),
+        ("string->number", Between(1, 2), string_to_number),
+        ("number->string", Between(1, 2), number_to_string),
+        ("readline", Exactly(1), readline),
+        ("slurp", Exactly(1), fn_str!(|f| { slurp(f) })),
+        ("<", AtLeast(1), |a| num_chain(a, |o| o == Ordering::Less)),
+        ("<=", AtLeast(1), |a| {
+            num_chain(a, |o| o != Ordering::Greater)
+        }),
+        (">", AtLeast(1), |a| {
+            num_chain(a, |o| o == Ordering::Greater)
+        }),
+        (">=", AtLeast(1), |a| num_chain(a, |o| o != Ordering::Less)),
+        ("+", AtLeast(0), add),
+        ("-", AtLeast(1), sub),
+        ("*", AtLeast(0), mul),
+        ("/", AtLeast(1), div),
+        ("quot", Exactly(2), quot),
+        ("rem", Exactly(2), rem),
+        ("mod", Exactly(2), modulo),
+        ("inc", Exactly(1), |a| {
+            num_op(&a[0], &Int(1), i64::checked_add, |x, y| x + y)
+        }),
+        ("dec", Exactly(1), |a| {
+            num_op(&a[0], &Int(1), i64::checked_sub, |x, y| x - y)
+        }),
+        ("max", AtLeast(1), |a| num_pick(a, Ordering::Greater)),
+        ("min", AtLeast(1), |a| num_pick(a, Ordering::Less)),
+        ("abs", Exactly(1), abs),
+        ("time-ms", Exactly(0), time_ms),
         (
-            "println",
-            func(|a| {
-                println!("{}", pr_seq(&a, false, "", "", " "));
-                Ok(Nil)
-            }),
+            "sequential?",
+            Exactly(1),
+            fn_is_type!(List(_, _), Vector(_, _), LazySeq(_)),
         ),
-        ("read-string", func(fn_str!(|s| { read_str(s) }))),
-        ("readline", func(readline)),
-        ("slurp", func(fn_str!(|f| { slurp(f) }))),
-        ("<", func(fn_t_int_int!(Bool, |i, j| { i < j }))),
-        ("<=", func(fn_t_int_int!(Bool, |i, j| { i <= j }))),
-        (">", func(fn_t_int_int!(Bool, |i, j| { i > j }))),
-        (">=", func(fn_t_int_int!(Bool, |i, j| { i >= j }))),
-        ("+", func(fn_t_int_int!(Int, |i, j| { i + j }))),
-        ("-", func(fn_t_int_int!(Int, |i, j| { i - j }))),
-        ("*", func(fn_t_int_int!(Int, |i, j| { i * j }))),
-        ("/", func(fn_t_int_int!(Int, |i, j| { i / j }))),
-        ("time-ms", func(time_ms)),
-        ("sequential?", func(fn_is_type!(List(_, _), Vector(_, _)))),
-        ("list", func(|a| Ok(list!(a)))),
-        ("list?", func(fn_is_type!(List(_, _)))),
-        ("vector", func(|a| Ok(vector!(a)))),
-        ("vector?", func(fn_is_type!(Vector(_, _)))),
-        ("hash-map", func(|a| hash_map(a))),
-        ("map?", func(fn_is_type!(Hash(_, _)))),
-        ("assoc", func(assoc)),
-        ("dissoc", func(dissoc)),
-        ("get", func(get)),
-        ("contains?", func(contains_q)),
-        ("keys", func(keys)),
-        ("vals", func(vals)),
-        ("vec", func(vec)),
-        ("cons", func(cons)),
-        ("concat", func(concat)),
-        ("empty?", func(|a| a[0].empty_q())),
-        ("nth", func(nth)),
-        ("first", func(first)),
-        ("rest", func(rest)),
-        ("count", func(|a| a[0].count())),
-        ("apply", func(apply)),
-        ("map", func(map)),
-        ("conj", func(conj)),
-        ("seq", func(seq)),
-        ("meta", func(|a| a[0].get_meta())),
-        ("with-meta", func(|a| a[0].clone().with_meta(&a[1]))),
-        ("atom", func(|a| Ok(atom(&a[0])))),
-        ("atom?", func(fn_is_type!(Atom(_)))),
-        ("deref", func(|a| a[0].deref())),
-        ("reset!", func(|a| a[0].reset_bang(&a[1]))),
-        ("swap!", func(|a| a[0].swap_bang(&a[1..].to_vec()))),
-    ]
+        ("list", AtLeast(0), |a| Ok(list!(a))),
+        ("list?", Exactly(1), fn_is_type!(List(_, _))),
+        ("vector", AtLeast(0), |a| Ok(vector!(a))),
+        ("vector?", Exactly(1), fn_is_type!(Vector(_, _))),
+        ("hash-map", AtLeast(0), |a| hash_map(a)),
+        ("map?", Exactly(1), fn_is_type!(Hash(_, _))),
+        ("hash-set", AtLeast(0), |a| Ok(hash_set(a))),
+        ("set", Exactly(1), set),
+        ("set?", Exactly(1), fn_is_type!(Set(_, _))),
+        ("disj", AtLeast(1), disj),
+        ("union", AtLeast(0), union),
+        ("intersection", AtLeast(1), intersection),
+        ("difference", AtLeast(1), difference),
+        ("assoc", AtLeast(1), assoc),
+        ("dissoc", AtLeast(1), dissoc),
+        ("get", Exactly(2), get),
+        ("get-in", Between(2, 3), get_in),
+        ("assoc-in", Exactly(3), assoc_in),
+        ("update", AtLeast(3), update),
+        ("update-in", AtLeast(3), update_in),
+        ("merge", AtLeast(0), merge),
+        ("contains?", Exactly(2), contains_q),
+        ("keys", Exactly(1), keys),
+        ("vals", Exactly(1), vals),
+        ("vec", Exactly(1), vec),
+        ("cons", Exactly(2), cons),
+        ("concat", AtLeast(0), concat),
+        ("empty?", Exactly(1), |a| a[0].empty_q()),
+        ("nth", Exactly(2), nth),
+        ("first", Exactly(1), first),
+        ("rest", Exactly(1), rest),
+        ("count", Exactly(1), |a| a[0].count()),
+        ("apply", AtLeast(2), apply),
+        ("map", Exactly(2), map),
+        ("filter", Exactly(2), filter),
+        ("remove", Exactly(2), remove),
+        ("take-while", Exactly(2), take_while),
+        ("drop-while", Exactly(2), drop_while),
+        ("take", Exactly(2), take),
+        ("drop", Exactly(2), drop),
+        ("range", Between(0, 3), range),
+        ("iterate", Exactly(2), |a| {
+            Ok(iterate(a[0].clone(), a[1].clone()))
+        }),
+        ("reduce", Between(2, 3), reduce),
+        ("some", Exactly(2), some),
+        ("every?", Exactly(2), every_q),
+        ("reverse", Exactly(1), reverse),
+        ("sort", Between(1, 2), sort),
+        ("sort-by", Between(2, 3), sort_by),
+        ("group-by", Exactly(2), group_by),
+        ("frequencies", Exactly(1), frequencies),
+        ("partition", Between(2, 4), partition),
+        ("interleave", AtLeast(0), interleave),
+        ("distinct", Exactly(1), distinct),
+        ("last", Exactly(1), last),
+        ("butlast", Exactly(1), butlast),
+        ("zipmap", Exactly(2), zipmap),
+        ("into", Exactly(2), into),
+        ("conj", AtLeast(1), conj),
+        ("seq", Exactly(1), seq),
+        ("meta", Exactly(1), |a| a[0].get_meta()),
+        ("with-meta", Exactly(2), |a| a[0].clone().with_meta(&a[1])),
+        ("atom", Exactly(1), |a| Ok(atom(&a[0]))),
+        ("atom?", Exactly(1), fn_is_type!(Atom(_))),
+        ("deref", Exactly(1), |a| a[0].deref()),
+        ("reset!", Exactly(2), |a| a[0].reset_bang(&a[1])),
+        ("swap!", AtLeast(2), |a| a[0].swap_bang(&a[1..].to_vec())),
+    ];
+    builtins
+        .into_iter()
+        .map(|(name, arity, f)| (name, func(name, arity, f)))
+        .collect()
 }
@@ -3,13 +3,14 @@ use std::rc::Rc;
 //use std::collections::HashMap;
 use fnv::FnvHashMap;
 
+use crate::types::Arity::{AtLeast, Exactly};
 use crate::types::MalErr::ErrString;
 use crate::types::MalVal::{List, Nil, Sym, Vector};
-use crate::types::{error, MalErr, MalRet, MalVal};
+use crate::types::{error, kind_error, special, MalErr, MalRet, MalVal, Symbol};
 
 #[derive(Debug)]
 pub struct EnvStruct {
-    data: RefCell<FnvHashMap<String, MalVal>>,
+    data: RefCell<FnvHashMap<Symbol, MalVal>>,
     pub outer: Option<Env>,
 }
 
@@ -26,14 +27,33 @@ pub fn env_new(outer: Option<Env>) -> Env {
 }
 
 // TODO: mbinds and exprs as & types
-pub fn env_bind(outer: Option<Env>, mbinds: MalVal, exprs: Vec<MalVal>) -> Result<Env, MalErr> {
+// name is the function's name for arity errors, Nil if it is anonymous
+pub fn env_bind(
+    outer: Option<Env>,
+    mbinds: MalVal,
+    exprs: Vec<MalVal>,
+    name: &MalVal,
+) -> Result<Env, MalErr> {
     let env = env_new(outer);
     match mbinds {
         List(binds, _) | Vector(binds, _) => {
+            let arity = match binds.iter().position(|b| matches!(b, Sym(special::AMP))) {
+                Some(i) => AtLeast(i),
+                None => Exactly(binds.len()),
+            };
+            let name = match name {
+                Sym(s) => s.name(),
+                _ => "fn*",
+            };
+            arity.check(name, exprs.len())?;
             for (i, b) in binds.iter().enumerate() {
                 match b {
-                    Sym(s) if s == "&" => {
-                        env_set(&env, binds[i + 1].clone(), list!(exprs[i..].to_vec()))?;
+                    Sym(special::AMP) => {
+                        let rest = match binds.get(i + 1) {
+                            Some(r) => r.clone(),
+                            None => return Err(ErrString("missing param after &".to_string())),
+                        };
+                        env_set(&env, rest, list!(exprs[i..].to_vec()))?;
                         break;
                     }
                     _ => {
@@ -47,7 +67,8 @@ pub fn env_bind(outer: Option<Env>, mbinds: MalVal, exprs: Vec<MalVal>) -> Resul
     }
 }
 
-pub fn env_find(env: &Env, key: &str) -> Option<Env> {
+#[allow(dead_code)] // only step8 and step9 look a symbol's env up
+pub fn env_find(env: &Env, key: &Symbol) -> Option<Env> {
     match (env.data.borrow().contains_key(key), env.outer.clone()) {
         (true, _) => Some(env.clone()),
         (false, Some(o)) => env_find(&o, key),
@@ -55,16 +76,35 @@ pub fn env_find(env: &Env, key: &str) -> Option<Env> {
     }
 }
 
+// Every name bound in env or the envs outside it
+#[allow(dead_code)] // only stepA's REPL completes names
+pub fn env_names(env: &Env) -> Vec<String> {
+    let mut names = vec![];
+    let mut e = Some(env);
+    while let Some(env) = e {
+        names.extend(env.data.borrow().keys().map(|s| s.to_string()));
+        e = env.outer.as_ref();
+    }
+    names
+}
+
+// The value bound to key, without building an error when there is none
+#[allow(dead_code)] // only stepA looks macros up this way
+pub fn env_lookup(env: &Env, key: &Symbol) -> Option<MalVal> {
+    let mut e = env;
+    loop {
+        if let Some(val) = e.data.borrow().get(key) {
+            return Some(val.clone());
+        }
+        e = e.outer.as_ref()?;
+    }
+}
+
 pub fn env_get(env: &Env, key: &MalVal) -> MalRet {
     match key {
-        Sym(ref s) => match env_find(env, s) {
-            Some(e) => Ok(e
-                .data
-                .borrow()
-                .get(s)
-                .ok_or(ErrString(format!("'{}' not found", s)))?
-                .clone()),
-            _ => error(&format!("'{}' not found", s)),
+        Sym(s) => match env_lookup(env, s) {
+            Some(val) => Ok(val),
+            None => kind_error("not-found", &format!("'{}' not found", s)),
         },
         _ => error("Env.get called with non-Str"),
     }
@@ -72,8 +112,8 @@ pub fn env_get(env: &Env, key: &MalVal) -> MalRet {
 
 pub fn env_set(env: &Env, key: MalVal, val: MalVal) -> MalRet {
     match key {
-        Sym(ref s) => {
-            env.data.borrow_mut().insert(s.to_string(), val.clone());
+        Sym(s) => {
+            env.data.borrow_mut().insert(s, val.clone());
             Ok(val)
         }
         _ => error("Env.set called with non-Str"),
@@ -81,5 +121,5 @@ pub fn env_set(env: &Env, key: MalVal, val: MalVal) -> MalRet {
 }
 
 pub fn env_sets(env: &Env, key: &str, val: MalVal) {
-    env.data.borrow_mut().insert(key.to_string(), val);
+    env.data.borrow_mut().insert(Symbol::new(key), val);
 }
@@ -1,18 +1,49 @@
+use crate::reader::ESCAPES;
 use crate::types::MalVal;
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
+use crate::types::MalVal::{
+    Atom, Bool, Char, Exc, Float, Func, Hash, Int, Keyword, LazySeq, List, MalFunc, Nil, Regex,
+    Set, Str, Sym, Vector,
+};
 
+// The inverse of the reader's unescaping: other control characters are
+// written as \uXXXX so the result stays printable
 fn escape_str(s: &str) -> String {
     s.chars()
-        .map(|c| match c {
-            '"' => "\\\"".to_string(),
-            '\n' => "\\n".to_string(),
-            '\\' => "\\\\".to_string(),
-            _ => c.to_string(),
+        .map(|c| match ESCAPES.iter().find(|&&(_, u)| u == c) {
+            Some(&(name, _)) => format!("\\{}", name),
+            None if c.is_control() => format!("\\u{:04x}", c as u32),
+            None => c.to_string(),
         })
         .collect::<Vec<String>>()
         .join("")
 }
 
+// Debug formatting always keeps a '.' or an exponent, so the output reads
+// back as a Float rather than an Int.
+fn pr_float(f: f64) -> String {
+    if f.is_nan() {
+        String::from("##NaN")
+    } else if f.is_infinite() {
+        String::from(if f > 0.0 { "##Inf" } else { "##-Inf" })
+    } else {
+        format!("{:?}", f)
+    }
+}
+
+// A character literal as the reader reads it back
+fn pr_char(c: char) -> String {
+    match c {
+        '\n' => String::from("\\newline"),
+        ' ' => String::from("\\space"),
+        '\t' => String::from("\\tab"),
+        '\r' => String::from("\\return"),
+        '\u{8}' => String::from("\\backspace"),
+        '\u{c}' => String::from("\\formfeed"),
+        c if c.is_control() => format!("\\u{:04x}", c as u32),
+        c => format!("\\{}", c),
+    }
+}
+
 impl MalVal {
     pub fn pr_str(&self, print_readably: bool) -> String {
         match self {
@@ -20,42 +51,74 @@ impl MalVal {
             Bool(true) => String::from("true"),
             Bool(false) => String::from("false"),
             Int(i) => format!("{}", i),
-            //Float(f)    => format!("{}", f),
+            Float(f) => pr_float(*f),
+            Keyword(k) => format!(":{}", k),
             Str(s) => {
-                if s.starts_with("\u{29e}") {
-                    format!(":{}", &s[2..])
-                } else if print_readably {
+                if print_readably {
                     format!("\"{}\"", escape_str(s))
                 } else {
                     s.clone()
                 }
             }
-            Sym(s) => s.clone(),
-            List(l, _) => pr_seq(&**l, print_readably, "(", ")", " "),
-            Vector(l, _) => pr_seq(&**l, print_readably, "[", "]", " "),
+            Char(c) if print_readably => pr_char(*c),
+            Char(c) => c.to_string(),
+            Sym(s) => s.to_string(),
+            List(l, _) => pr_seq(l, print_readably, "(", ")", " "),
+            Vector(l, _) => pr_seq(l, print_readably, "[", "]", " "),
             Hash(hm, _) => {
                 let l: Vec<MalVal> = hm
                     .iter()
-                    .flat_map(|(k, v)| vec![Str(k.to_string()), v.clone()])
+                    .flat_map(|(k, v)| vec![k.clone(), v.clone()])
                     .collect();
                 pr_seq(&l, print_readably, "{", "}", " ")
             }
-            Func(f, _) => format!("#<fn {:?}>", f),
+            Set(hs, _) => pr_seq(hs, print_readably, "#{", "}", " "),
+            // stops at an element that fails to realize; callers that can
+            // report the error realize the value before printing it
+            LazySeq(_) => {
+                let l: Vec<MalVal> = self.iter().map_while(Result::ok).collect();
+                pr_seq(&l, print_readably, "(", ")", " ")
+            }
+            Func(f, ..) => format!("#<fn {:?}>", f),
             MalFunc {
                 ast: a, params: p, ..
             } => format!("(fn* {} {})", p.pr_str(true), a.pr_str(true)),
             Atom(a) => format!("(atom {})", a.borrow().pr_str(true)),
+            Regex(re) if print_readably => format!("#\"{}\"", re.as_str()),
+            Regex(re) => re.as_str().to_string(),
+            // catch* has always bound internal errors as their message, so
+            // they still print as one
+            Exc {
+                msg,
+                internal: true,
+                ..
+            } if print_readably => format!("\"{}\"", escape_str(msg)),
+            Exc {
+                msg, data, cause, ..
+            } if print_readably => {
+                let cause = match **cause {
+                    Nil => String::new(),
+                    ref c => format!(" :cause {}", c.pr_str(true)),
+                };
+                format!(
+                    "#error {{:message \"{}\" :data {}{}}}",
+                    escape_str(msg),
+                    data.pr_str(true),
+                    cause
+                )
+            }
+            Exc { msg, .. } => msg.clone(),
         }
     }
 }
 
-pub fn pr_seq(
-    seq: &Vec<MalVal>,
+pub fn pr_seq<'a>(
+    seq: impl IntoIterator<Item = &'a MalVal>,
     print_readably: bool,
     start: &str,
     end: &str,
     join: &str,
 ) -> String {
-    let strs: Vec<String> = seq.iter().map(|x| x.pr_str(print_readably)).collect();
+    let strs: Vec<String> = seq.into_iter().map(|x| x.pr_str(print_readably)).collect();
     format!("{}{}{}", start, strs.join(join), end)
 }
@@ -1,14 +1,25 @@
-use regex::{Captures, Regex};
+use fnv::FnvHashMap;
+use regex::Regex;
+use std::cell::RefCell;
 use std::rc::Rc;
 
-use crate::types::MalErr::ErrString;
-use crate::types::MalVal::{Bool, Int, List, Nil, Str, Sym, Vector};
-use crate::types::{error, hash_map, MalErr, MalRet, MalVal};
+use crate::types::Arity::Exactly;
+use crate::types::MalErr::{ErrKind, ErrString};
+use crate::types::MalVal::{Bool, Char, Float, Hash, Int, List, Nil, Set, Str, Sym, Vector};
+use crate::types::{
+    error, ex_info, func, hash_map, hash_set, keyword, kind_error, re_pattern, special, src_pos,
+    sym, MalArgs, MalErr, MalRet, MalVal, Symbol,
+};
 
 #[derive(Debug, Clone)]
 struct Reader {
     tokens: Vec<String>,
+    // line and column of each token
+    positions: Vec<(usize, usize)>,
+    source: Option<String>,
     pos: usize,
+    // inside a #(...), where another one would be ambiguous
+    in_fn_literal: bool,
 }
 
 impl Reader {
@@ -27,57 +38,176 @@ impl Reader {
             .ok_or(ErrString("underflow".to_string()))?
             .to_string())
     }
+    // Error tagged with the position of the token at idx
+    fn error_at(&self, idx: usize, msg: &str) -> MalRet {
+        Err(ErrKind("reader", msg.to_string()).at(&self.pos_meta(idx)))
+    }
+    // Position meta for the token at idx, or Nil when the source is unnamed
+    fn pos_meta(&self, idx: usize) -> MalVal {
+        match (&self.source, self.positions.get(idx)) {
+            (Some(file), Some(&(line, column))) => src_pos(file, line, column),
+            _ => Nil,
+        }
+    }
 }
 
-fn tokenize(str: &str) -> Vec<String> {
+fn tokenize(str: &str) -> (Vec<String>, Vec<(usize, usize)>) {
     lazy_static! {
         static ref RE: Regex = Regex::new(
-            r###"[\s,]*(~@|[\[\]{}()'`~^@]|"(?:\\.|[^\\"])*"?|;.*|[^\s\[\]{}('"`,;)]+)"###
+            r###"[\s,]*(~@|#[{(_]|[\[\]{}()'`~^@]|#?"(?:\\.|[^\\"])*"?|;.*|\\.[^\s\[\]{}('"`,;)]*|[^\s\[\]{}('"`,;)]+)"###
         )
         .unwrap();
     }
 
     let mut res = vec![];
+    let mut positions = vec![];
+    let (mut line, mut column, mut last) = (1, 1, 0);
     for cap in RE.captures_iter(str) {
+        let start = cap.get(1).unwrap().start();
+        for c in str[last..start].chars() {
+            if c == '\n' {
+                line += 1;
+                column = 1;
+            } else {
+                column += 1;
+            }
+        }
+        last = start;
         if cap[1].starts_with(";") {
             continue;
         }
         res.push(String::from(&cap[1]));
+        positions.push((line, column));
     }
-    res
+    (res, positions)
 }
 
-fn unescape_str(s: &str) -> String {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(r#"\\(.)"#).unwrap();
+// String escapes the printer writes and the reader reads back: the char
+// after the backslash and the char it stands for. Any other char can be
+// written as \uXXXX.
+pub const ESCAPES: [(char, char); 6] = [
+    ('"', '"'),
+    ('\\', '\\'),
+    ('n', '\n'),
+    ('t', '\t'),
+    ('r', '\r'),
+    ('0', '\0'),
+];
+
+fn unescape_str(s: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        // the tokenizer only ends a string on an unescaped quote
+        let e = chars.next().unwrap_or('\\');
+        if e == 'u' {
+            let hex: String = chars.by_ref().take(4).collect();
+            match u32::from_str_radix(&hex, 16)
+                .ok()
+                .and_then(std::char::from_u32)
+            {
+                Some(u) if hex.len() == 4 => out.push(u),
+                _ => return Err(format!("invalid unicode escape \\u{}", hex)),
+            }
+        } else {
+            match ESCAPES.iter().find(|&&(name, _)| name == e) {
+                Some(&(_, u)) => out.push(u),
+                None => return Err(format!("unknown escape sequence \\{}", e)),
+            }
+        }
     }
-    RE.replace_all(&s, |caps: &Captures| {
-        format!("{}", if &caps[1] == "n" { "\n" } else { &caps[1] })
-    })
-    .to_string()
+    Ok(out)
 }
 
-fn read_atom(rdr: &mut Reader) -> MalRet {
+// The number a token spells, None if it is not a number literal
+pub fn read_number(token: &str) -> Option<MalRet> {
     lazy_static! {
         static ref INT_RE: Regex = Regex::new(r"^-?[0-9]+$").unwrap();
-        static ref STR_RE: Regex = Regex::new(r#""(?:\\.|[^\\"])*""#).unwrap();
+        static ref FLOAT_RE: Regex =
+            Regex::new(r"^-?(?:[0-9]+\.[0-9]*|\.[0-9]+|[0-9]+)(?:[eE][-+]?[0-9]+)?$").unwrap();
+    }
+    if INT_RE.is_match(token) {
+        Some(match token.parse() {
+            Ok(i) => Ok(Int(i)),
+            Err(_) => kind_error(
+                "reader",
+                &format!("integer literal out of range: {}", token),
+            ),
+        })
+    } else if FLOAT_RE.is_match(token) {
+        Some(Ok(Float(token.parse().unwrap())))
+    } else {
+        None
     }
+}
+
+// A character literal without its backslash: a single character, a name
+// such as newline, or a code point written uXXXX
+fn read_char(name: &str) -> Option<char> {
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => return Some(c),
+        (None, _) => return None,
+        _ => (),
+    }
+    match name {
+        "newline" => Some('\n'),
+        "space" => Some(' '),
+        "tab" => Some('\t'),
+        "return" => Some('\r'),
+        "backspace" => Some('\u{8}'),
+        "formfeed" => Some('\u{c}'),
+        _ if name.starts_with('u') && name.len() == 5 => u32::from_str_radix(&name[1..], 16)
+            .ok()
+            .and_then(std::char::from_u32),
+        _ => None,
+    }
+}
+
+lazy_static! {
+    static ref STR_RE: Regex = Regex::new(r#""(?:\\.|[^\\"])*""#).unwrap();
+}
+
+fn read_atom(rdr: &mut Reader) -> MalRet {
     let token = rdr.next()?;
     match &token[..] {
         "nil" => Ok(Nil),
         "false" => Ok(Bool(false)),
         "true" => Ok(Bool(true)),
+        "##Inf" => Ok(Float(f64::INFINITY)),
+        "##-Inf" => Ok(Float(f64::NEG_INFINITY)),
+        "##NaN" => Ok(Float(f64::NAN)),
         _ => {
-            if INT_RE.is_match(&token) {
-                Ok(Int(token.parse().unwrap()))
+            if let Some(n) = read_number(&token) {
+                n
+            } else if token.starts_with("#\"") && STR_RE.is_match(&token[1..]) {
+                // regex literals are not unescaped: #"\d" is the pattern \d
+                match re_pattern(&token[2..token.len() - 1]) {
+                    Err(ErrKind(_, msg)) => rdr.error_at(rdr.pos - 1, &msg),
+                    re => re,
+                }
+            } else if token.starts_with("#\"") {
+                rdr.error_at(rdr.pos - 1, "expected '\"', got EOF")
             } else if STR_RE.is_match(&token) {
-                Ok(Str(unescape_str(&token[1..token.len() - 1])))
+                match unescape_str(&token[1..token.len() - 1]) {
+                    Ok(s) => Ok(Str(s)),
+                    Err(msg) => rdr.error_at(rdr.pos - 1, &msg),
+                }
             } else if token.starts_with("\"") {
-                error("expected '\"', got EOF")
-            } else if token.starts_with(":") {
-                Ok(Str(format!("\u{29e}{}", &token[1..])))
+                rdr.error_at(rdr.pos - 1, "expected '\"', got EOF")
+            } else if let Some(name) = token.strip_prefix('\\') {
+                match read_char(name) {
+                    Some(c) => Ok(Char(c)),
+                    None => rdr.error_at(rdr.pos - 1, &format!("unsupported character: {}", token)),
+                }
+            } else if let Some(name) = token.strip_prefix(':') {
+                Ok(keyword(name))
             } else {
-                Ok(Sym(token.to_string()))
+                Ok(sym(&token))
             }
         }
     }
@@ -85,11 +215,15 @@ fn read_atom(rdr: &mut Reader) -> MalRet {
 
 fn read_seq(rdr: &mut Reader, end: &str) -> MalRet {
     let mut seq: Vec<MalVal> = vec![];
-    rdr.next()?;
+    let meta = rdr.pos_meta(rdr.pos);
+    let start = rdr.next()?;
     loop {
+        skip_discarded(rdr)?;
         let token = match rdr.peek() {
             Ok(t) => t,
-            Err(_) => return error(&format!("expected '{}', got EOF", end)),
+            Err(_) => {
+                return Err(ErrKind("reader", format!("expected '{}', got EOF", end)).at(&meta))
+            }
         };
         if token == end {
             break;
@@ -97,60 +231,386 @@ fn read_seq(rdr: &mut Reader, end: &str) -> MalRet {
         seq.push(read_form(rdr)?)
     }
     let _ = rdr.next();
-    match end {
-        ")" => Ok(list!(seq)),
-        "]" => Ok(vector!(seq)),
-        "}" => hash_map(seq),
-        _ => error("read_seq unknown end value"),
+    let mut form = match &start[..] {
+        "(" | "#(" => list!(seq),
+        "[" => vector!(seq),
+        "{" => hash_map(seq)?,
+        "#{" => hash_set(seq),
+        _ => return error("read_seq unknown start value"),
+    };
+    match meta {
+        Nil => Ok(form),
+        _ => form.with_meta(&meta),
+    }
+}
+
+// Skip the forms commented out with #_
+fn skip_discarded(rdr: &mut Reader) -> Result<(), MalErr> {
+    while let Ok("#_") = rdr.peek().as_deref() {
+        let meta = rdr.pos_meta(rdr.pos);
+        let _ = rdr.next();
+        if rdr.pos == rdr.tokens.len() {
+            let msg = "expected a form after #_, got EOF".to_string();
+            return Err(ErrKind("reader", msg).at(&meta));
+        }
+        read_form(rdr)?;
+    }
+    Ok(())
+}
+
+// #(...) reads as (fn* [%1 .. %n & %&] (...)), n being the highest %n the
+// body uses. A bare % is %1.
+fn read_fn_literal(rdr: &mut Reader) -> MalRet {
+    if rdr.in_fn_literal {
+        return rdr.error_at(rdr.pos, "nested #()s are not allowed");
+    }
+    rdr.in_fn_literal = true;
+    let body = read_seq(rdr, ")");
+    rdr.in_fn_literal = false;
+    let (mut n, mut rest) = (0, false);
+    let body = fn_literal_args(&body?, &mut n, &mut rest);
+    let mut params: Vec<MalVal> = (1..=n).map(|i| sym(&format!("%{}", i))).collect();
+    if rest {
+        params.push(Sym(special::AMP));
+        params.push(sym("%&"));
+    }
+    Ok(list![Sym(special::FN), vector!(params), body])
+}
+
+// Rename % to %1 throughout a form, noting the highest %n and any %&
+fn fn_literal_args(form: &MalVal, n: &mut usize, rest: &mut bool) -> MalVal {
+    match form {
+        Sym(s) if s.starts_with('%') => match &s[1..] {
+            "" => {
+                *n = (*n).max(1);
+                sym("%1")
+            }
+            "&" => {
+                *rest = true;
+                form.clone()
+            }
+            i => {
+                if let Ok(i) = i.parse::<usize>() {
+                    *n = (*n).max(i);
+                }
+                form.clone()
+            }
+        },
+        List(l, meta) => List(
+            l.iter().map(|x| fn_literal_args(x, n, rest)).collect(),
+            meta.clone(),
+        ),
+        Vector(l, meta) => Vector(
+            l.iter().map(|x| fn_literal_args(x, n, rest)).collect(),
+            meta.clone(),
+        ),
+        Hash(hm, meta) => Hash(
+            hm.iter()
+                .map(|(k, v)| (fn_literal_args(k, n, rest), fn_literal_args(v, n, rest)))
+                .collect(),
+            meta.clone(),
+        ),
+        Set(hs, meta) => Set(
+            hs.iter().map(|x| fn_literal_args(x, n, rest)).collect(),
+            meta.clone(),
+        ),
+        _ => form.clone(),
+    }
+}
+
+thread_local! {
+    // reader functions for tagged literals #tag form, by tag
+    static TAGS: RefCell<FnvHashMap<Symbol, MalVal>> = RefCell::new(default_tags());
+}
+
+// mal has no date or uuid types, so #inst and #uuid check the literal and
+// read it as a string. #error reads back a printed exception.
+fn default_tags() -> FnvHashMap<Symbol, MalVal> {
+    let mut tags = FnvHashMap::default();
+    tags.insert(Symbol::new("inst"), func("inst", Exactly(1), read_inst));
+    tags.insert(Symbol::new("uuid"), func("uuid", Exactly(1), read_uuid));
+    tags.insert(Symbol::new("error"), func("error", Exactly(1), read_error));
+    tags
+}
+
+fn read_inst(a: MalArgs) -> MalRet {
+    lazy_static! {
+        static ref INST_RE: Regex =
+            Regex::new(r"^\d{4}-\d{2}-\d{2}(T\d{2}:\d{2}(:\d{2}(\.\d+)?)?(Z|[-+]\d{2}:\d{2})?)?$")
+                .unwrap();
+    }
+    match a[0] {
+        Str(ref s) if INST_RE.is_match(s) => Ok(a[0].clone()),
+        _ => kind_error(
+            "reader",
+            &format!("#inst: invalid timestamp {}", a[0].pr_str(true)),
+        ),
+    }
+}
+
+fn read_uuid(a: MalArgs) -> MalRet {
+    lazy_static! {
+        static ref UUID_RE: Regex = Regex::new(
+            r"^[[:xdigit:]]{8}-[[:xdigit:]]{4}-[[:xdigit:]]{4}-[[:xdigit:]]{4}-[[:xdigit:]]{12}$"
+        )
+        .unwrap();
+    }
+    match a[0] {
+        Str(ref s) if UUID_RE.is_match(s) => Ok(a[0].clone()),
+        _ => kind_error(
+            "reader",
+            &format!("#uuid: invalid uuid {}", a[0].pr_str(true)),
+        ),
+    }
+}
+
+fn read_error(a: MalArgs) -> MalRet {
+    if let Hash(ref hm, _) = a[0] {
+        if let Some(Str(msg)) = hm.get(&keyword("message")) {
+            let get = |k| hm.get(&keyword(k)).cloned().unwrap_or(Nil);
+            return Ok(ex_info(msg, get("data"), get("cause")));
+        }
+    }
+    kind_error(
+        "reader",
+        &format!(
+            "#error: expected a map with a :message, got {}",
+            a[0].pr_str(true)
+        ),
+    )
+}
+
+// Make #tag form read as the result of calling f with the form
+#[allow(dead_code)] // steps 1 and 2 have no core to register tags from
+pub fn register_tag(tag: Symbol, f: MalVal) {
+    TAGS.with(|tags| tags.borrow_mut().insert(tag, f));
+}
+
+fn read_tagged(rdr: &mut Reader) -> MalRet {
+    let idx = rdr.pos;
+    let token = rdr.next()?;
+    let tag = Symbol::new(&token[1..]);
+    let form = read_form(rdr)?;
+    match TAGS.with(|tags| tags.borrow().get(&tag).cloned()) {
+        Some(f) => f.apply(vec![form]),
+        None => rdr.error_at(idx, &format!("no reader function for tag {}", tag)),
     }
 }
 
 fn read_form(rdr: &mut Reader) -> MalRet {
-    let token = rdr.peek()?;
+    skip_discarded(rdr)?;
+    let token = match rdr.peek() {
+        Ok(t) => t,
+        Err(_) => return rdr.error_at(rdr.pos.saturating_sub(1), "expected a form, got EOF"),
+    };
     match &token[..] {
         "'" => {
             let _ = rdr.next();
-            Ok(list![Sym("quote".to_string()), read_form(rdr)?])
+            Ok(list![sym("quote"), read_form(rdr)?])
         }
         "`" => {
             let _ = rdr.next();
-            Ok(list![Sym("quasiquote".to_string()), read_form(rdr)?])
+            Ok(list![sym("quasiquote"), read_form(rdr)?])
         }
         "~" => {
             let _ = rdr.next();
-            Ok(list![Sym("unquote".to_string()), read_form(rdr)?])
+            Ok(list![sym("unquote"), read_form(rdr)?])
         }
         "~@" => {
             let _ = rdr.next();
-            Ok(list![Sym("splice-unquote".to_string()), read_form(rdr)?])
+            Ok(list![sym("splice-unquote"), read_form(rdr)?])
         }
         "^" => {
             let _ = rdr.next();
             let meta = read_form(rdr)?;
-            Ok(list![Sym("with-meta".to_string()), read_form(rdr)?, meta])
+            Ok(list![sym("with-meta"), read_form(rdr)?, meta])
         }
         "@" => {
             let _ = rdr.next();
-            Ok(list![Sym("deref".to_string()), read_form(rdr)?])
+            Ok(list![sym("deref"), read_form(rdr)?])
         }
-        ")" => error("unexpected ')'"),
+        ")" => rdr.error_at(rdr.pos, "unexpected ')'"),
         "(" => read_seq(rdr, ")"),
-        "]" => error("unexpected ']'"),
+        "]" => rdr.error_at(rdr.pos, "unexpected ']'"),
         "[" => read_seq(rdr, "]"),
-        "}" => error("unexpected '}'"),
+        "}" => rdr.error_at(rdr.pos, "unexpected '}'"),
         "{" => read_seq(rdr, "}"),
+        "#{" => read_seq(rdr, "}"),
+        "#(" => read_fn_literal(rdr),
+        _ if token.starts_with('#') && !token.starts_with("##") && !token.starts_with("#\"") => {
+            read_tagged(rdr)
+        }
         _ => read_atom(rdr),
     }
 }
 
-pub fn read_str(str: String) -> MalRet {
-    let tokens = tokenize(&str);
-    //println!("tokens: {:?}", tokens);
-    if tokens.len() == 0 {
-        return error("no input");
-    }
-    read_form(&mut Reader {
+// Forms read from a named source carry their position as meta
+fn new_reader(str: &str, source: Option<&str>) -> Reader {
+    let (tokens, positions) = tokenize(str);
+    Reader {
         pos: 0,
         tokens: tokens,
-    })
+        positions,
+        source: source.map(|s| s.to_string()),
+        in_fn_literal: false,
+    }
+}
+
+pub fn read_str(str: String, source: Option<&str>) -> MalRet {
+    let mut rdr = new_reader(&str, source);
+    //println!("tokens: {:?}", rdr.tokens);
+    skip_discarded(&mut rdr)?;
+    if rdr.pos == rdr.tokens.len() {
+        return error("no input");
+    }
+    read_form(&mut rdr)
+}
+
+// Whether more lines could complete the input, because a string or a
+// collection is still open at its end, or it ends in a prefix such as ' or
+// #_ still waiting for its form. Anything else, errors included, is left
+// for read_str to report.
+#[allow(dead_code)] // only stepA's REPL reads multi-line input
+pub fn is_incomplete(str: &str) -> bool {
+    let mut depth = 0;
+    let mut prefixed = false;
+    for token in tokenize(str).0 {
+        match &token[..] {
+            t if t.starts_with(';') => continue,
+            "(" | "[" | "{" | "#(" | "#{" => depth += 1,
+            ")" | "]" | "}" if depth == 0 => return false,
+            ")" | "]" | "}" => depth -= 1,
+            // an unterminated string runs to the end of the input
+            t if t.starts_with('"') && !STR_RE.is_match(t) => return true,
+            t if t.starts_with("#\"") && !STR_RE.is_match(&t[1..]) => return true,
+            _ => {}
+        }
+        prefixed = is_prefix(&token);
+    }
+    depth > 0 || prefixed
+}
+
+// Tokens that read as a wrapper around the form after them
+fn is_prefix(token: &str) -> bool {
+    match token {
+        "'" | "`" | "~" | "~@" | "@" | "^" | "#_" => true,
+        "#(" | "#{" => false,
+        // a tag, as in #inst "..."
+        t => t.starts_with('#') && !t.starts_with("##") && !t.starts_with("#\""),
+    }
+}
+
+// The first token of the innermost list still open at the end of str, once
+// something follows it, so the REPL can hint at what the list calls
+#[allow(dead_code)] // only stepA's REPL gives hints
+pub fn open_list_head(str: &str) -> Option<String> {
+    let tokens = tokenize(str).0;
+    let mut heads = vec![];
+    for (i, token) in tokens.iter().enumerate() {
+        match &token[..] {
+            "(" | "#(" => heads.push(Some(i + 1)),
+            "[" | "{" | "#{" => heads.push(None),
+            ")" | "]" | "}" => {
+                heads.pop();
+            }
+            _ => {}
+        }
+    }
+    let head = heads.pop()??;
+    // the head itself is still being typed
+    if head + 1 == tokens.len() && !str.ends_with(|c: char| c.is_whitespace() || c == ',') {
+        return None;
+    }
+    tokens.get(head).cloned()
+}
+
+// The top-level forms of a source, read one at a time so that each can be
+// evaluated before the next is read. Ends after the first error.
+pub struct Forms {
+    rdr: Reader,
+    // index of the first token of the form last read
+    start: usize,
+    failed: bool,
+}
+
+impl Forms {
+    // Position meta of the form last read, for errors it didn't locate itself
+    #[allow(dead_code)] // only the step with a native load-file uses it
+    pub fn pos(&self) -> MalVal {
+        self.rdr.pos_meta(self.start)
+    }
+}
+
+impl Iterator for Forms {
+    type Item = MalRet;
+
+    fn next(&mut self) -> Option<MalRet> {
+        if self.failed {
+            return None;
+        }
+        let form = match skip_discarded(&mut self.rdr) {
+            Ok(()) if self.rdr.pos == self.rdr.tokens.len() => return None,
+            Ok(()) => {
+                self.start = self.rdr.pos;
+                read_form(&mut self.rdr)
+            }
+            Err(e) => Err(e),
+        };
+        self.failed = form.is_err();
+        Some(form)
+    }
+}
+
+#[allow(dead_code)]
+pub fn read_forms(str: String, source: Option<&str>) -> Forms {
+    Forms {
+        rdr: new_reader(&str, source),
+        start: 0,
+        failed: false,
+    }
+}
+
+// Unlike read_str, every form is read, so trailing junk is an error
+#[allow(dead_code)]
+pub fn read_all(str: String, source: Option<&str>) -> Result<Vec<MalVal>, MalErr> {
+    read_forms(str, source).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_incomplete;
+
+    #[test]
+    fn balanced_input_is_complete() {
+        assert!(!is_incomplete("(+ 1 2)"));
+        assert!(!is_incomplete("(def! f (fn* [x]\n  {:a x}))"));
+        assert!(!is_incomplete("\"a (\" ; (["));
+        assert!(!is_incomplete("'x #_ y"));
+        assert!(!is_incomplete(""));
+    }
+
+    #[test]
+    fn open_collection_or_string_is_incomplete() {
+        assert!(is_incomplete("(defn foo [x]"));
+        assert!(is_incomplete("[1 {:a"));
+        assert!(is_incomplete("#{1 #(+ %"));
+        assert!(is_incomplete("(str \"a\nb"));
+        assert!(is_incomplete("#\"a("));
+    }
+
+    #[test]
+    fn extra_closer_is_left_to_the_reader() {
+        assert!(!is_incomplete("(+ 1 2))"));
+        assert!(!is_incomplete("] ("));
+    }
+
+    #[test]
+    fn dangling_prefix_is_incomplete() {
+        for input in &["#_", "[1 #_", "'", "(list `", "~", "~@", "@", "^", "#inst"] {
+            assert!(is_incomplete(input), "{:?}", input);
+        }
+        assert!(is_incomplete("' ; comment"));
+        assert!(!is_incomplete("@a"));
+        assert!(!is_incomplete("##NaN"));
+    }
 }
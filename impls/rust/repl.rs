@@ -0,0 +1,146 @@
+use std::borrow::Cow;
+
+use rustyline::completion::Completer;
+use rustyline::highlight::{Highlighter, MatchingBracketHighlighter};
+use rustyline::hint::{Hint, Hinter};
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{Context, Helper};
+
+use crate::env::{env_get, env_names, Env};
+use crate::reader::{is_incomplete, open_list_head};
+use crate::types::MalVal::{MalFunc, Sym};
+use crate::types::{Symbol, PREDEFINED};
+
+// Characters that end a symbol, as the reader's tokenizer splits them
+const DELIMITERS: &str = "[]{}()'\"`,;~^@";
+
+// Line editing for the REPL: completes the names bound in its env, shows
+// the parameters of the function being called, highlights the bracket
+// matching the one at the cursor and keeps reading while a form is open
+pub struct ReplHelper {
+    env: Env,
+    brackets: MatchingBracketHighlighter,
+}
+
+impl ReplHelper {
+    pub fn new(env: Env) -> ReplHelper {
+        ReplHelper {
+            env,
+            brackets: MatchingBracketHighlighter::new(),
+        }
+    }
+}
+
+impl Helper for ReplHelper {}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = word_start(line, pos);
+        let prefix = &line[start..pos];
+        let mut names: Vec<String> = env_names(&self.env)
+            .into_iter()
+            .chain(PREDEFINED.iter().map(|name| name.to_string()))
+            .filter(|name| name.starts_with(prefix))
+            .collect();
+        names.sort();
+        names.dedup();
+        Ok((start, names))
+    }
+}
+
+// Byte offset of the name that ends at pos, just past the whitespace or
+// delimiter before it, which may take more than one byte
+fn word_start(line: &str, pos: usize) -> usize {
+    line[..pos]
+        .char_indices()
+        .rev()
+        .find(|&(_, c)| c.is_whitespace() || DELIMITERS.contains(c))
+        .map_or(0, |(i, c)| i + c.len_utf8())
+}
+
+// A function's parameter list, shown after the cursor but never inserted
+pub struct ParamsHint(String);
+
+impl Hint for ParamsHint {
+    fn display(&self) -> &str {
+        &self.0
+    }
+
+    fn completion(&self) -> Option<&str> {
+        None
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = ParamsHint;
+
+    fn hint(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> Option<ParamsHint> {
+        if pos < line.len() {
+            return None;
+        }
+        let head = Symbol::find(&open_list_head(line)?)?;
+        match env_get(&self.env, &Sym(head)) {
+            Ok(MalFunc { params, .. }) => {
+                let sep = if line.ends_with(char::is_whitespace) {
+                    ""
+                } else {
+                    " "
+                };
+                Some(ParamsHint(format!("{}{}", sep, params.pr_str(true))))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Highlighter for ReplHelper {
+    fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
+        self.brackets.highlight(line, pos)
+    }
+
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        Cow::Owned(format!("\x1b[2m{}\x1b[0m", hint))
+    }
+
+    fn highlight_char(&self, line: &str, pos: usize) -> bool {
+        self.brackets.highlight_char(line, pos)
+    }
+}
+
+impl Validator for ReplHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        if is_incomplete(ctx.input()) {
+            Ok(ValidationResult::Incomplete)
+        } else {
+            Ok(ValidationResult::Valid(None))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::word_start;
+
+    #[test]
+    fn word_starts_after_a_delimiter() {
+        assert_eq!(word_start("(str ab", 7), 5);
+        assert_eq!(word_start("(ab", 3), 1);
+        assert_eq!(word_start("ab", 2), 0);
+        assert_eq!(word_start("(map in", 4), 1);
+    }
+
+    #[test]
+    fn word_starts_on_a_char_boundary() {
+        // U+00A0 takes two bytes and U+3000 three
+        assert_eq!(word_start("(str\u{a0}ab", 8), 6);
+        assert_eq!(word_start("x\u{3000}map", 7), 4);
+        assert_eq!(word_start("\u{e9}(ab", 5), 3);
+    }
+}
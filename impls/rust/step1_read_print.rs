@@ -1,6 +1,7 @@
 #[macro_use]
 extern crate lazy_static;
 extern crate fnv;
+extern crate im_rc;
 extern crate itertools;
 extern crate regex;
 
@@ -32,7 +33,7 @@ fn main() {
                 rl.add_history_entry(&line);
                 rl.save_history(".mal-history").unwrap();
                 if line.len() > 0 {
-                    match reader::read_str(line) {
+                    match reader::read_str(line, None) {
                         Ok(mv) => {
                             println!("{}", mv.pr_str(true));
                         }
@@ -5,6 +5,7 @@ use fnv::FnvHashMap;
 #[macro_use]
 extern crate lazy_static;
 extern crate fnv;
+extern crate im_rc;
 extern crate itertools;
 extern crate regex;
 
@@ -15,9 +16,10 @@ use rustyline::Editor;
 #[macro_use]
 #[allow(dead_code)]
 mod types;
+use crate::types::Arity::Exactly;
 use crate::types::MalErr::ErrString;
-use crate::types::MalVal::{Hash, Int, List, Nil, Sym, Vector};
-use crate::types::{error, format_error, func, MalArgs, MalErr, MalRet, MalVal};
+use crate::types::MalVal::{Hash, Int, List, Nil, Set, Sym, Vector};
+use crate::types::{error, format_error, func, MalArgs, MalErr, MalMap, MalRet, MalSet, MalVal};
 mod printer;
 mod reader;
 // TODO: figure out a way to avoid including env
@@ -28,14 +30,14 @@ pub type Env = FnvHashMap<String, MalVal>;
 
 // read
 fn read(str: &str) -> MalRet {
-    reader::read_str(str.to_string())
+    reader::read_str(str.to_string(), None)
 }
 
 // eval
 fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
     match ast {
         Sym(sym) => Ok(env
-            .get(sym)
+            .get(sym.name())
             .ok_or(ErrString(format!("'{}' not found", sym)))?
             .clone()),
         List(v, _) => {
@@ -53,11 +55,18 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
             Ok(vector!(lst))
         }
         Hash(hm, _) => {
-            let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+            let mut new_hm = MalMap::default();
             for (k, v) in hm.iter() {
-                new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
+                new_hm.insert(eval(k.clone(), env.clone())?, eval(v.clone(), env.clone())?);
             }
-            Ok(Hash(Rc::new(new_hm), Rc::new(Nil)))
+            Ok(Hash(new_hm, Rc::new(Nil)))
+        }
+        Set(hs, _) => {
+            let mut new_hs = MalSet::default();
+            for v in hs.iter() {
+                new_hs.insert(eval(v.clone(), env.clone())?);
+            }
+            Ok(Set(new_hs, Rc::new(Nil)))
         }
         _ => Ok(ast.clone()),
     }
@@ -72,7 +81,7 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
             match eval_ast(&ast, &env)? {
                 List(ref el, _) => {
                     let ref f = el[0].clone();
-                    f.apply(el[1..].to_vec())
+                    f.apply(el.iter().skip(1).cloned().collect())
                 }
                 _ => error("expected a list"),
             }
@@ -107,10 +116,22 @@ fn main() {
     }
 
     let mut repl_env = Env::default();
-    repl_env.insert("+".to_string(), func(|a: MalArgs| int_op(|i, j| i + j, a)));
-    repl_env.insert("-".to_string(), func(|a: MalArgs| int_op(|i, j| i - j, a)));
-    repl_env.insert("*".to_string(), func(|a: MalArgs| int_op(|i, j| i * j, a)));
-    repl_env.insert("/".to_string(), func(|a: MalArgs| int_op(|i, j| i / j, a)));
+    repl_env.insert(
+        "+".to_string(),
+        func("+", Exactly(2), |a: MalArgs| int_op(|i, j| i + j, a)),
+    );
+    repl_env.insert(
+        "-".to_string(),
+        func("-", Exactly(2), |a: MalArgs| int_op(|i, j| i - j, a)),
+    );
+    repl_env.insert(
+        "*".to_string(),
+        func("*", Exactly(2), |a: MalArgs| int_op(|i, j| i * j, a)),
+    );
+    repl_env.insert(
+        "/".to_string(),
+        func("/", Exactly(2), |a: MalArgs| int_op(|i, j| i / j, a)),
+    );
 
     loop {
         let readline = rl.readline("user> ");
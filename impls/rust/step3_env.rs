@@ -1,11 +1,11 @@
 use std::rc::Rc;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
 use itertools::Itertools;
 
 #[macro_use]
 extern crate lazy_static;
 extern crate fnv;
+extern crate im_rc;
 extern crate itertools;
 extern crate regex;
 
@@ -16,8 +16,9 @@ use rustyline::Editor;
 #[macro_use]
 #[allow(dead_code)]
 mod types;
-use crate::types::MalVal::{Hash, Int, List, Nil, Sym, Vector};
-use crate::types::{error, format_error, func, MalArgs, MalErr, MalRet, MalVal};
+use crate::types::Arity::Exactly;
+use crate::types::MalVal::{Hash, Int, List, Nil, Set, Sym, Vector};
+use crate::types::{error, format_error, special, func, MalArgs, MalErr, MalMap, MalRet, MalSet, MalVal};
 mod env;
 mod printer;
 mod reader;
@@ -25,7 +26,7 @@ use crate::env::{env_get, env_new, env_set, env_sets, Env};
 
 // read
 fn read(str: &str) -> MalRet {
-    reader::read_str(str.to_string())
+    reader::read_str(str.to_string(), None)
 }
 
 // eval
@@ -47,11 +48,18 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
             Ok(vector!(lst))
         }
         Hash(hm, _) => {
-            let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+            let mut new_hm = MalMap::default();
             for (k, v) in hm.iter() {
-                new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
+                new_hm.insert(eval(k.clone(), env.clone())?, eval(v.clone(), env.clone())?);
             }
-            Ok(Hash(Rc::new(new_hm), Rc::new(Nil)))
+            Ok(Hash(new_hm, Rc::new(Nil)))
+        }
+        Set(hs, _) => {
+            let mut new_hs = MalSet::default();
+            for v in hs.iter() {
+                new_hs.insert(eval(v.clone(), env.clone())?);
+            }
+            Ok(Set(new_hs, Rc::new(Nil)))
         }
         _ => Ok(ast.clone()),
     }
@@ -65,10 +73,10 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
             }
             let a0 = &l[0];
             match a0 {
-                Sym(ref a0sym) if a0sym == "def!" => {
+                Sym(special::DEF) => {
                     env_set(&env, l[1].clone(), eval(l[2].clone(), env.clone())?)
                 }
-                Sym(ref a0sym) if a0sym == "let*" => {
+                Sym(special::LET) => {
                     let let_env = env_new(Some(env.clone()));
                     let (a1, a2) = (l[1].clone(), l[2].clone());
                     match a1 {
@@ -97,7 +105,7 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
                 _ => match eval_ast(&ast, &env)? {
                     List(ref el, _) => {
                         let ref f = el[0].clone();
-                        f.apply(el[1..].to_vec())
+                        f.apply(el.iter().skip(1).cloned().collect())
                     }
                     _ => error("expected a list"),
                 },
@@ -133,10 +141,26 @@ fn main() {
     }
 
     let repl_env = env_new(None);
-    env_sets(&repl_env, "+", func(|a: MalArgs| int_op(|i, j| i + j, a)));
-    env_sets(&repl_env, "-", func(|a: MalArgs| int_op(|i, j| i - j, a)));
-    env_sets(&repl_env, "*", func(|a: MalArgs| int_op(|i, j| i * j, a)));
-    env_sets(&repl_env, "/", func(|a: MalArgs| int_op(|i, j| i / j, a)));
+    env_sets(
+        &repl_env,
+        "+",
+        func("+", Exactly(2), |a: MalArgs| int_op(|i, j| i + j, a)),
+    );
+    env_sets(
+        &repl_env,
+        "-",
+        func("-", Exactly(2), |a: MalArgs| int_op(|i, j| i - j, a)),
+    );
+    env_sets(
+        &repl_env,
+        "*",
+        func("*", Exactly(2), |a: MalArgs| int_op(|i, j| i * j, a)),
+    );
+    env_sets(
+        &repl_env,
+        "/",
+        func("/", Exactly(2), |a: MalArgs| int_op(|i, j| i / j, a)),
+    );
 
     loop {
         let readline = rl.readline("user> ");
@@ -1,11 +1,11 @@
 use std::rc::Rc;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
 use itertools::Itertools;
 
 #[macro_use]
 extern crate lazy_static;
 extern crate fnv;
+extern crate im_rc;
 extern crate itertools;
 extern crate regex;
 
@@ -15,8 +15,8 @@ use rustyline::Editor;
 
 #[macro_use]
 mod types;
-use crate::types::MalVal::{Bool, Hash, List, MalFunc, Nil, Sym, Vector};
-use crate::types::{error, format_error, MalArgs, MalErr, MalRet, MalVal};
+use crate::types::MalVal::{Bool, Hash, List, MalFunc, Nil, Set, Sym, Vector};
+use crate::types::{error, format_error, special, MalArgs, MalErr, MalMap, MalRet, MalSet, MalVal};
 mod env;
 mod printer;
 mod reader;
@@ -26,7 +26,7 @@ mod core;
 
 // read
 fn read(str: &str) -> MalRet {
-    reader::read_str(str.to_string())
+    reader::read_str(str.to_string(), None)
 }
 
 // eval
@@ -48,11 +48,18 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
             Ok(vector!(lst))
         }
         Hash(hm, _) => {
-            let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+            let mut new_hm = MalMap::default();
             for (k, v) in hm.iter() {
-                new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
+                new_hm.insert(eval(k.clone(), env.clone())?, eval(v.clone(), env.clone())?);
             }
-            Ok(Hash(Rc::new(new_hm), Rc::new(Nil)))
+            Ok(Hash(new_hm, Rc::new(Nil)))
+        }
+        Set(hs, _) => {
+            let mut new_hs = MalSet::default();
+            for v in hs.iter() {
+                new_hs.insert(eval(v.clone(), env.clone())?);
+            }
+            Ok(Set(new_hs, Rc::new(Nil)))
         }
         _ => Ok(ast.clone()),
     }
@@ -66,10 +73,10 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
             }
             let a0 = &l[0];
             match a0 {
-                Sym(ref a0sym) if a0sym == "def!" => {
+                Sym(special::DEF) => {
                     env_set(&env, l[1].clone(), eval(l[2].clone(), env.clone())?)
                 }
-                Sym(ref a0sym) if a0sym == "let*" => {
+                Sym(special::LET) => {
                     let let_env = env_new(Some(env.clone()));
                     let (a1, a2) = (l[1].clone(), l[2].clone());
                     match a1 {
@@ -95,11 +102,11 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
                     };
                     eval(a2, let_env)
                 }
-                Sym(ref a0sym) if a0sym == "do" => match eval_ast(&list!(l[1..].to_vec()), &env)? {
+                Sym(special::DO) => match eval_ast(&List(l.skip(1), Rc::new(Nil)), &env)? {
                     List(el, _) => Ok(el.last().unwrap_or(&Nil).clone()),
                     _ => error("invalid do form"),
                 },
-                Sym(ref a0sym) if a0sym == "if" => {
+                Sym(special::IF) => {
                     let cond = eval(l[1].clone(), env.clone())?;
                     match cond {
                         Bool(false) | Nil if l.len() >= 4 => eval(l[3].clone(), env.clone()),
@@ -108,7 +115,7 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
                         _ => Ok(Nil),
                     }
                 }
-                Sym(ref a0sym) if a0sym == "fn*" => {
+                Sym(special::FN) => {
                     let (a1, a2) = (l[1].clone(), l[2].clone());
                     Ok(MalFunc {
                         eval: eval,
@@ -117,12 +124,13 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
                         params: Rc::new(a1),
                         is_macro: false,
                         meta: Rc::new(Nil),
+                        name: Rc::new(Nil),
                     })
                 }
                 _ => match eval_ast(&ast, &env)? {
                     List(ref el, _) => {
                         let ref f = el[0].clone();
-                        f.apply(el[1..].to_vec())
+                        f.apply(el.iter().skip(1).cloned().collect())
                     }
                     _ => error("expected a list"),
                 },
@@ -1,15 +1,18 @@
 #![allow(non_snake_case)]
 
+use std::cell::{Cell, RefCell};
+use std::io::IsTerminal;
 use std::rc::Rc;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
 use itertools::Itertools;
 
 #[macro_use]
 extern crate lazy_static;
 extern crate fnv;
+extern crate im_rc;
 extern crate itertools;
 extern crate regex;
+extern crate stacker;
 
 extern crate rustyline;
 use rustyline::error::ReadlineError;
@@ -17,37 +20,46 @@ use rustyline::Editor;
 
 #[macro_use]
 mod types;
-use crate::types::MalErr::{ErrMalVal, ErrString};
-use crate::types::MalVal::{Bool, Func, Hash, List, MalFunc, Nil, Str, Sym, Vector};
-use crate::types::{error, format_error, MalArgs, MalErr, MalRet, MalVal};
+use crate::types::Arity::Exactly;
+use crate::types::MalErr::ErrString;
+use crate::types::MalVal::{
+    Bool, Exc, Func, Hash, Keyword, List, MalFunc, Nil, Set, Str, Sym, Vector,
+};
+use crate::types::{
+    error, format_error, frame_depth, func, keyword, kind_error, is_src_pos, lazy_seq,
+    push_frame, replace_frame, special, sym, truncate_frames, Frame, MalArgs, MalErr, MalMap,
+    MalRet, MalSet, MalSeq, MalVal,
+};
 mod env;
 mod printer;
 mod reader;
-use crate::env::{env_bind, env_find, env_get, env_new, env_set, env_sets, Env};
+mod repl;
+use crate::env::{env_bind, env_get, env_lookup, env_new, env_set, env_sets, Env};
+use crate::repl::ReplHelper;
 #[macro_use]
 mod core;
 
 // read
 fn read(str: &str) -> MalRet {
-    reader::read_str(str.to_string())
+    reader::read_str(str.to_string(), None)
 }
 
 // eval
 
-fn qq_iter(elts: &MalArgs) -> MalVal {
+fn qq_iter(elts: &MalSeq) -> MalVal {
     let mut acc = list![];
     for elt in elts.iter().rev() {
         if let List(v, _) = elt {
             if v.len() == 2 {
                 if let Sym(ref s) = v[0] {
-                    if s == "splice-unquote" {
-                        acc = list![Sym("concat".to_string()), v[1].clone(), acc];
+                    if *s == special::SPLICE_UNQUOTE {
+                        acc = list![sym("concat"), v[1].clone(), acc];
                         continue;
                     }
                 }
             }
         }
-        acc = list![Sym("cons".to_string()), quasiquote(&elt), acc];
+        acc = list![sym("cons"), quasiquote(&elt), acc];
     }
     return acc;
 }
@@ -57,15 +69,15 @@ fn quasiquote(ast: &MalVal) -> MalVal {
         List(v, _) => {
             if v.len() == 2 {
                 if let Sym(ref s) = v[0] {
-                    if s == "unquote" {
+                    if *s == special::UNQUOTE {
                         return v[1].clone();
                     }
                 }
             }
             return qq_iter(&v);
         },
-        Vector(v, _) => return list![Sym("vec".to_string()), qq_iter(&v)],
-        Hash(_, _) | Sym(_)=> return list![Sym("quote".to_string()), ast.clone()],
+        Vector(v, _) => return list![sym("vec"), qq_iter(&v)],
+        Hash(_, _) | Set(_, _) | Sym(_)=> return list![Sym(special::QUOTE), ast.clone()],
         _ => ast.clone(),
     }
 }
@@ -73,11 +85,8 @@ fn quasiquote(ast: &MalVal) -> MalVal {
 fn is_macro_call(ast: &MalVal, env: &Env) -> Option<(MalVal, MalArgs)> {
     match ast {
         List(v, _) => match v[0] {
-            Sym(ref s) => match env_find(env, s) {
-                Some(e) => match env_get(&e, &v[0]) {
-                    Ok(f @ MalFunc { is_macro: true, .. }) => Some((f, v[1..].to_vec())),
-                    _ => None,
-                },
+            Sym(ref s) => match env_lookup(env, s) {
+                Some(f @ MalFunc { is_macro: true, .. }) => Some((f, v.iter().skip(1).cloned().collect())),
                 _ => None,
             },
             _ => None,
@@ -86,6 +95,8 @@ fn is_macro_call(ast: &MalVal, env: &Env) -> Option<(MalVal, MalArgs)> {
     }
 }
 
+// Not inlined into eval_tco, whose frame every nested eval keeps on the stack
+#[inline(never)]
 fn macroexpand(mut ast: MalVal, env: &Env) -> (bool, MalRet) {
     let mut was_expanded = false;
     while let Some((mf, args)) = is_macro_call(&ast, env) {
@@ -100,6 +111,8 @@ fn macroexpand(mut ast: MalVal, env: &Env) -> (bool, MalRet) {
     ((was_expanded, Ok(ast)))
 }
 
+// Not inlined into eval_tco either
+#[inline(never)]
 fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
     match ast {
         Sym(_) => Ok(env_get(&env, &ast)?),
@@ -118,22 +131,245 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
             Ok(vector!(lst))
         }
         Hash(hm, _) => {
-            let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+            let mut new_hm = MalMap::default();
             for (k, v) in hm.iter() {
-                new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
+                new_hm.insert(eval(k.clone(), env.clone())?, eval(v.clone(), env.clone())?);
             }
-            Ok(Hash(Rc::new(new_hm), Rc::new(Nil)))
+            Ok(Hash(new_hm, Rc::new(Nil)))
+        }
+        Set(hs, _) => {
+            let mut new_hs = MalSet::default();
+            for v in hs.iter() {
+                new_hs.insert(eval(v.clone(), env.clone())?);
+            }
+            Ok(Set(new_hs, Rc::new(Nil)))
         }
         _ => Ok(ast.clone()),
     }
 }
 
-fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
+// A (catch* [type-or-pred] sym body) clause of try*
+struct Catch {
+    filter: Option<MalVal>,
+    sym: MalVal,
+    body: MalVal,
+}
+
+fn parse_catch(c: &MalSeq) -> Result<Catch, MalErr> {
+    let (filter, sym, body) = match c.len() {
+        3 => (None, &c[1], &c[2]),
+        4 => (Some(c[1].clone()), &c[2], &c[3]),
+        _ => {
+            return Err(ErrString(format!(
+                "catch*: expected (catch* sym body) or (catch* type-or-pred sym body), got {}",
+                List(c.clone(), Rc::new(Nil)).pr_str(true)
+            )))
+        }
+    };
+    match sym {
+        Sym(_) => Ok(Catch {
+            filter,
+            sym: sym.clone(),
+            body: body.clone(),
+        }),
+        _ => Err(ErrString(format!(
+            "catch*: binding must be a symbol, got {}",
+            sym.pr_str(true)
+        ))),
+    }
+}
+
+// A type keyword matches the :type in the exception's ex-data, anything else
+// is evaluated to a predicate called with the exception.
+fn catch_matches(filter: &Option<MalVal>, exc: &MalVal, env: &Env) -> Result<bool, MalErr> {
+    match filter {
+        None => Ok(true),
+        Some(kw @ Keyword(_)) => match exc {
+            Exc { data, .. } => match **data {
+                Hash(ref hm, _) => Ok(hm.get(&keyword("type")) == Some(kw)),
+                _ => Ok(false),
+            },
+            _ => Ok(false),
+        },
+        Some(pred) => match eval(pred.clone(), env.clone())?.apply(vec![exc.clone()])? {
+            Bool(false) | Nil => Ok(false),
+            _ => Ok(true),
+        },
+    }
+}
+
+fn eval_catch(catches: &[Catch], e: MalErr, env: &Env) -> MalRet {
+    let exc = e.exc();
+    for c in catches.iter() {
+        if catch_matches(&c.filter, &exc, env)? {
+            let catch_env = env_bind(
+                Some(env.clone()),
+                list!(vec![c.sym.clone()]),
+                vec![exc.clone()],
+                &Nil,
+            )?;
+            return eval(c.body.clone(), catch_env);
+        }
+    }
+    Err(e)
+}
+
+// (try* expr (catch* [type-or-pred] sym body)... (finally* body...))
+// The first matching catch* handles the error, an unmatched error propagates.
+// finally* runs in every case and only its errors replace the result.
+fn eval_try(l: &MalSeq, env: &Env) -> MalRet {
+    if l.len() < 2 {
+        return error("try*: missing body");
+    }
+    let mut catches = vec![];
+    let mut finally = MalSeq::new();
+    for (i, clause) in l.iter().skip(2).enumerate() {
+        match clause {
+            List(c, _) if c.front() == Some(&Sym(special::CATCH)) => {
+                catches.push(parse_catch(c)?)
+            }
+            List(c, _) if c.front() == Some(&Sym(special::FINALLY)) => {
+                if i + 3 != l.len() {
+                    return error("try*: finally* must be the last clause");
+                }
+                finally = c.skip(1);
+            }
+            _ => {
+                return Err(ErrString(format!(
+                    "try*: expected a catch* or finally* clause, got {}",
+                    clause.pr_str(true)
+                )))
+            }
+        }
+    }
+
+    let ret = match eval(l[1].clone(), env.clone()) {
+        Err(e) => eval_catch(&catches, e, env),
+        res => res,
+    };
+    for f in finally.iter() {
+        eval(f.clone(), env.clone())?;
+    }
+    ret
+}
+
+// A new env with each symbol of a let* or loop* binding vector bound to its
+// value, evaluated after the ones before it. Also returns the symbols.
+fn eval_bindings(form: &str, binds: &MalVal, env: &Env) -> Result<(Env, MalArgs), MalErr> {
+    let binds = match binds {
+        List(binds, _) | Vector(binds, _) => binds,
+        _ => return Err(ErrString(format!("{} with non-List bindings", form))),
+    };
+    let env = env_new(Some(env.clone()));
+    let mut syms = vec![];
+    for (b, e) in binds.iter().tuples() {
+        match b {
+            Sym(_) => {
+                env_set(&env, b.clone(), eval(e.clone(), env.clone())?)?;
+                syms.push(b.clone());
+            }
+            _ => return Err(ErrString(format!("{} with non-Sym binding", form))),
+        }
+    }
+    Ok((env, syms))
+}
+
+fn eval_defmacro(l: &MalSeq, env: &Env) -> MalRet {
+    match eval(l[2].clone(), env.clone())? {
+        MalFunc {
+            eval,
+            ast,
+            env,
+            params,
+            ..
+        } => env_set(
+            &env,
+            l[1].clone(),
+            MalFunc {
+                eval,
+                ast,
+                env: env.clone(),
+                params,
+                is_macro: true,
+                meta: Rc::new(Nil),
+                name: Rc::new(l[1].clone()),
+            },
+        ),
+        _ => error("set_macro on non-function"),
+    }
+}
+
+// (lazy-seq body...) delays evaluating the body until the seq is used
+fn eval_lazy_seq(l: &MalSeq, env: &Env) -> MalRet {
+    let body = match l.len() {
+        1 => Nil,
+        2 => l[1].clone(),
+        _ => {
+            let mut body = l.skip(1);
+            body.push_front(Sym(special::DO));
+            List(body, Rc::new(Nil))
+        }
+    };
+    let env = env.clone();
+    Ok(lazy_seq(move || eval(body.clone(), env.clone())))
+}
+
+// Errors are tagged with the position of the innermost located list form
+// that was being evaluated when they were raised, and with the call frames
+// active at that point. Each eval holds at most one frame: tail calls
+// replace it. Symbols and literals cannot recurse or push a frame, so they
+// skip that bookkeeping; their errors are traced by the enclosing eval.
+fn eval(ast: MalVal, env: Env) -> MalRet {
+    match ast {
+        Sym(_) => return env_get(&env, &ast),
+        List(..) | Vector(..) | Hash(..) | Set(..) => (),
+        _ => return Ok(ast),
+    }
+    let mut pos = Nil;
+    let depth = frame_depth();
+    let ret = eval_guarded(|| eval_tco(ast, env, &mut pos)).map_err(|e| e.at(&pos).traced());
+    truncate_frames(depth);
+    ret
+}
+
+// Nested evals are limited to MAL_MAX_EVAL_DEPTH (default DEFAULT_MAX_DEPTH).
+// The rust stack grows by STACK_SEGMENT whenever less than STACK_RED_ZONE is
+// left, so deep non-tail recursion hits that limit instead of overflowing.
+// The default keeps runaway recursion cheap to hit. A nested eval takes
+// about 2.5KB of stack in a release build, so programs that recurse deeper
+// can raise the limit, to a few hundred thousand levels in under 2GB.
+const DEFAULT_MAX_DEPTH: usize = 20_000;
+const STACK_RED_ZONE: usize = 256 * 1024;
+const STACK_SEGMENT: usize = 8 * 1024 * 1024;
+
+thread_local! {
+    static EVAL_DEPTH: Cell<usize> = const { Cell::new(0) };
+    static MAX_EVAL_DEPTH: Cell<usize> = const { Cell::new(DEFAULT_MAX_DEPTH) };
+}
+
+fn eval_guarded(f: impl FnOnce() -> MalRet) -> MalRet {
+    let depth = EVAL_DEPTH.with(|d| d.get());
+    if depth >= MAX_EVAL_DEPTH.with(|m| m.get()) {
+        return kind_error("stack-overflow", "stack depth exceeded");
+    }
+    EVAL_DEPTH.with(|d| d.set(depth + 1));
+    let ret = stacker::maybe_grow(STACK_RED_ZONE, STACK_SEGMENT, f);
+    EVAL_DEPTH.with(|d| d.set(depth));
+    ret
+}
+
+fn eval_tco(mut ast: MalVal, mut env: Env, pos: &mut MalVal) -> MalRet {
     let ret: MalRet;
+    let mut framed = false;
+    // bindings, body and enclosing env of the loop* whose tail is being evaluated
+    let mut recur_target: Option<(Rc<MalArgs>, MalVal, Env)> = None;
 
     'tco: loop {
         ret = match ast.clone() {
-            List(l, _) => {
+            List(l, meta) => {
+                if is_src_pos(&meta) {
+                    *pos = (*meta).clone();
+                }
                 if l.len() == 0 {
                     return Ok(ast);
                 }
@@ -151,103 +387,65 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                 }
                 let a0 = &l[0];
                 match a0 {
-                    Sym(ref a0sym) if a0sym == "def!" => {
-                        env_set(&env, l[1].clone(), eval(l[2].clone(), env.clone())?)
+                    Sym(special::DEF) => {
+                        let val = eval(l[2].clone(), env.clone())?.named(&l[1]);
+                        env_set(&env, l[1].clone(), val)
                     }
-                    Sym(ref a0sym) if a0sym == "let*" => {
-                        env = env_new(Some(env.clone()));
-                        let (a1, a2) = (l[1].clone(), l[2].clone());
-                        match a1 {
-                            List(ref binds, _) | Vector(ref binds, _) => {
-                                for (b, e) in binds.iter().tuples() {
-                                    match b {
-                                        Sym(_) => {
-                                            let _ = env_set(
-                                                &env,
-                                                b.clone(),
-                                                eval(e.clone(), env.clone())?,
-                                            );
-                                        }
-                                        _ => {
-                                            return error("let* with non-Sym binding");
-                                        }
-                                    }
-                                }
-                            }
-                            _ => {
-                                return error("let* with non-List bindings");
-                            }
-                        };
-                        ast = a2;
+                    Sym(special::LET) => {
+                        env = eval_bindings("let*", &l[1], &env)?.0;
+                        ast = l[2].clone();
                         continue 'tco;
                     }
-                    Sym(ref a0sym) if a0sym == "quote" => Ok(l[1].clone()),
-                    Sym(ref a0sym) if a0sym == "quasiquoteexpand" => Ok(quasiquote(&l[1])),
-                    Sym(ref a0sym) if a0sym == "quasiquote" => {
-                        ast = quasiquote(&l[1]);
+                    Sym(special::LOOP) => {
+                        if l.len() != 3 {
+                            return error("loop* expects a binding vector and a body");
+                        }
+                        let outer = env.clone();
+                        let (loop_env, syms) = eval_bindings("loop*", &l[1], &outer)?;
+                        env = loop_env;
+                        recur_target = Some((Rc::new(syms), l[2].clone(), outer));
+                        ast = l[2].clone();
                         continue 'tco;
                     }
-                    Sym(ref a0sym) if a0sym == "defmacro!" => {
-                        let (a1, a2) = (l[1].clone(), l[2].clone());
-                        let r = eval(a2, env.clone())?;
-                        match r {
-                            MalFunc {
-                                eval,
-                                ast,
-                                env,
-                                params,
-                                ..
-                            } => Ok(env_set(
-                                &env,
-                                a1.clone(),
-                                MalFunc {
-                                    eval: eval,
-                                    ast: ast.clone(),
-                                    env: env.clone(),
-                                    params: params.clone(),
-                                    is_macro: true,
-                                    meta: Rc::new(Nil),
-                                },
-                            )?),
-                            _ => error("set_macro on non-function"),
+                    Sym(special::RECUR) => {
+                        let (syms, body, outer) = match recur_target {
+                            Some(ref target) => target.clone(),
+                            None => return error("recur must be in tail position of loop*"),
+                        };
+                        let mut args = vec![];
+                        for a in l.iter().skip(1) {
+                            args.push(eval(a.clone(), env.clone())?);
+                        }
+                        Exactly(syms.len()).check("recur", args.len())?;
+                        env = env_new(Some(outer));
+                        for (sym, val) in syms.iter().zip(args) {
+                            env_set(&env, sym.clone(), val)?;
                         }
+                        ast = body;
+                        continue 'tco;
+                    }
+                    Sym(special::QUOTE) => Ok(l[1].clone()),
+                    Sym(special::QUASIQUOTEEXPAND) => Ok(quasiquote(&l[1])),
+                    Sym(special::QUASIQUOTE) => {
+                        ast = quasiquote(&l[1]);
+                        continue 'tco;
                     }
-                    Sym(ref a0sym) if a0sym == "macroexpand" => {
+                    Sym(special::DEFMACRO) => eval_defmacro(&l, &env),
+                    Sym(special::MACROEXPAND) => {
                         match macroexpand(l[1].clone(), &env) {
                             (_, Ok(new_ast)) => Ok(new_ast),
                             (_, e) => return e,
                         }
                     }
-                    Sym(ref a0sym) if a0sym == "try*" => match eval(l[1].clone(), env.clone()) {
-                        Err(ref e) if l.len() >= 3 => {
-                            let exc = match e {
-                                ErrMalVal(mv) => mv.clone(),
-                                ErrString(s) => Str(s.to_string()),
-                            };
-                            match l[2].clone() {
-                                List(c, _) => {
-                                    let catch_env = env_bind(
-                                        Some(env.clone()),
-                                        list!(vec![c[1].clone()]),
-                                        vec![exc],
-                                    )?;
-                                    eval(c[2].clone(), catch_env)
-                                }
-                                _ => error("invalid catch block"),
-                            }
-                        }
-                        res => res,
-                    },
-                    Sym(ref a0sym) if a0sym == "do" => {
-                        match eval_ast(&list!(l[1..l.len() - 1].to_vec()), &env)? {
-                            List(_, _) => {
-                                ast = l.last().unwrap_or(&Nil).clone();
-                                continue 'tco;
-                            }
-                            _ => error("invalid do form"),
+                    Sym(special::TRY) => eval_try(&l, &env),
+                    Sym(special::DO) => {
+                        for a in l.iter().skip(1).take(l.len() - 2) {
+                            eval(a.clone(), env.clone())?;
                         }
+                        ast = l.last().unwrap_or(&Nil).clone();
+                        continue 'tco;
                     }
-                    Sym(ref a0sym) if a0sym == "if" => {
+                    Sym(special::IF) => {
                         let cond = eval(l[1].clone(), env.clone())?;
                         match cond {
                             Bool(false) | Nil if l.len() >= 4 => {
@@ -262,7 +460,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                             _ => Ok(Nil),
                         }
                     }
-                    Sym(ref a0sym) if a0sym == "fn*" => {
+                    Sym(special::FN) => {
                         let (a1, a2) = (l[1].clone(), l[2].clone());
                         Ok(MalFunc {
                             eval: eval,
@@ -271,38 +469,53 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                             params: Rc::new(a1),
                             is_macro: false,
                             meta: Rc::new(Nil),
+                            name: Rc::new(Nil),
                         })
                     }
-                    Sym(ref a0sym) if a0sym == "eval" => {
+                    Sym(special::LAZY_SEQ) => eval_lazy_seq(&l, &env),
+                    Sym(special::EVAL) => {
                         ast = eval(l[1].clone(), env.clone())?;
                         while let Some(ref e) = env.clone().outer {
                             env = e.clone();
                         }
+                        recur_target = None;
                         continue 'tco;
                     }
-                    _ => match eval_ast(&ast, &env)? {
-                        List(ref el, _) => {
-                            let ref f = el[0].clone();
-                            let args = el[1..].to_vec();
-                            match f {
-                                Func(_, _) => f.apply(args),
-                                MalFunc {
-                                    ast: mast,
-                                    env: menv,
-                                    params,
-                                    ..
-                                } => {
-                                    let a = &**mast;
-                                    let p = &**params;
-                                    env = env_bind(Some(menv.clone()), p.clone(), args)?;
-                                    ast = a.clone();
-                                    continue 'tco;
+                    _ => {
+                        let f = eval(a0.clone(), env.clone())?;
+                        let mut args = Vec::with_capacity(l.len() - 1);
+                        for a in l.iter().skip(1) {
+                            args.push(eval(a.clone(), env.clone())?);
+                        }
+                        match &f {
+                            Func(..) | Keyword(..) | Set(..) => f.apply(args),
+                            MalFunc {
+                                ast: mast,
+                                env: menv,
+                                params,
+                                name,
+                                ..
+                            } => {
+                                let a = &**mast;
+                                let p = &**params;
+                                env = env_bind(Some(menv.clone()), p.clone(), args, name)?;
+                                let frame = Frame {
+                                    name: (**name).clone(),
+                                    form: ast.clone(),
+                                };
+                                if framed {
+                                    replace_frame(frame);
+                                } else {
+                                    push_frame(frame);
+                                    framed = true;
                                 }
-                                _ => error("attempt to call non-function"),
+                                recur_target = None;
+                                ast = a.clone();
+                                continue 'tco;
                             }
+                            _ => error("attempt to call non-function"),
                         }
-                        _ => error("expected a list"),
-                    },
+                    }
                 }
             }
             _ => eval_ast(&ast, &env),
@@ -322,19 +535,51 @@ fn print(ast: &MalVal) -> String {
 fn rep(str: &str, env: &Env) -> Result<String, MalErr> {
     let ast = read(str)?;
     let exp = eval(ast, env.clone())?;
+    exp.realize()?;
     Ok(print(&exp))
 }
 
+thread_local! {
+    // The env load-file evaluates in, whichever env it is called from
+    static REPL_ENV: RefCell<Option<Env>> = const { RefCell::new(None) };
+}
+
+// Reads and evaluates the file's forms one at a time, so a form can use
+// what the ones before it defined (reader tags included) and an error
+// stops the load at the form it came from
+fn load_file(a: MalArgs) -> MalRet {
+    let f = match a[0] {
+        Str(ref f) => f,
+        _ => return error("load-file: expecting a file name"),
+    };
+    let src = std::fs::read_to_string(f).map_err(|e| ErrString(e.to_string()))?;
+    let env = REPL_ENV.with(|e| e.borrow().clone()).unwrap();
+    let mut forms = reader::read_forms(src, Some(f));
+    while let Some(form) = forms.next() {
+        eval(form?, env.clone()).map_err(|e| e.at(&forms.pos()))?;
+    }
+    Ok(Nil)
+}
+
 fn main() {
-    let mut args = std::env::args();
-    let arg1 = args.nth(1);
+    let mut args = std::env::args().skip(1).peekable();
+    // The run script passes --raw for the test harness, which expects an
+    // error for an open form and no escape sequences in the echoed input
+    let multi_line = args.next_if(|a| a == "--raw").is_none();
+    let arg1 = args.next();
 
-    // `()` can be used when no completer is required
-    let mut rl = Editor::<()>::new();
+    let mut rl = Editor::<ReplHelper>::new();
     if rl.load_history(".mal-history").is_err() {
         eprintln!("No previous history.");
     }
 
+    if let Ok(depth) = std::env::var("MAL_MAX_EVAL_DEPTH") {
+        match depth.parse() {
+            Ok(depth) => MAX_EVAL_DEPTH.with(|m| m.set(depth)),
+            Err(_) => eprintln!("Ignoring invalid MAL_MAX_EVAL_DEPTH {:?}", depth),
+        }
+    }
+
     // core.rs: defined using rust
     let repl_env = env_new(None);
     for (k, v) in core::ns() {
@@ -345,10 +590,14 @@ fn main() {
     // core.mal: defined using the language itself
     let _ = rep("(def! *host-language* \"rust\")", &repl_env);
     let _ = rep("(def! not (fn* (a) (if a false true)))", &repl_env);
-    let _ = rep(
-        "(def! load-file (fn* (f) (eval (read-string (str \"(do \" (slurp f) \"\nnil)\")))))",
+    env_sets(
         &repl_env,
+        "load-file",
+        func("load-file", Exactly(1), load_file),
     );
+    REPL_ENV.with(|e| *e.borrow_mut() = Some(repl_env.clone()));
+    let _ = rep("(def! _loop-split (fn* (bs) (if (empty? bs) [[] []] (let* [g (gensym) r (_loop-split (rest (rest bs)))] [(concat [g (nth bs 1)] (nth r 0)) (concat [(first bs) g] (nth r 1))]))))", &repl_env);
+    let _ = rep("(defmacro! loop (fn* (bindings & body) (let* [r (_loop-split bindings)] `(loop* ~(vec (nth r 0)) (let* ~(destructure (nth r 1)) (do ~@body))))))", &repl_env);
     let _ = rep("(defmacro! cond (fn* (& xs) (if (> (count xs) 0) (list 'if (first xs) (if (> (count xs) 1) (nth xs 1) (throw \"odd number of forms to cond\")) (cons 'cond (rest (rest xs)))))))", &repl_env);
 
     // Invoked with arguments
@@ -364,20 +613,38 @@ fn main() {
 
     // main repl loop
     let _ = rep("(println (str \"Mal [\" *host-language* \"]\"))", &repl_env);
+    if multi_line {
+        rl.set_helper(Some(ReplHelper::new(repl_env.clone())));
+    }
+    // The helper's validator keeps a form open only for a terminal, so lines
+    // piped in are gathered here instead
+    let piped = !std::io::stdin().is_terminal();
+    // lines of a form that is still open, read under the continuation prompt
+    let mut input = String::new();
     loop {
-        let readline = rl.readline("user> ");
+        let readline = rl.readline(if input.is_empty() { "user> " } else { "  ... " });
         match readline {
             Ok(line) => {
-                rl.add_history_entry(&line);
+                input.push_str(&line);
+                if multi_line && piped && reader::is_incomplete(&input) {
+                    // lines read from a pipe keep their newline
+                    if !input.ends_with('\n') {
+                        input.push('\n');
+                    }
+                    continue;
+                }
+                rl.add_history_entry(&input);
                 rl.save_history(".mal-history").unwrap();
-                if line.len() > 0 {
-                    match rep(&line, &repl_env) {
+                if !input.is_empty() {
+                    match rep(&input, &repl_env) {
                         Ok(out) => println!("{}", out),
                         Err(e) => println!("Error: {}", format_error(e)),
                     }
                 }
+                input.clear();
             }
-            Err(ReadlineError::Interrupted) => continue,
+            // drops a partly typed form
+            Err(ReadlineError::Interrupted) => input.clear(),
             Err(ReadlineError::Eof) => break,
             Err(err) => {
                 println!("Error: {:?}", err);
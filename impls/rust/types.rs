@@ -1,25 +1,35 @@
 use std::cell::RefCell;
-use std::rc::Rc;
+use std::fmt;
+use std::hash::{Hash as StdHash, Hasher};
+use std::ops::Deref;
+use std::rc::{Rc, Weak};
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
+use fnv::{FnvBuildHasher, FnvHashMap, FnvHasher};
 use itertools::Itertools;
 
 use crate::env::{env_bind, Env};
-use crate::types::MalErr::{ErrMalVal, ErrString};
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
+use crate::types::MalErr::{ErrAt, ErrKind, ErrMalVal, ErrString, ErrTrace};
+use crate::types::MalVal::{
+    Atom, Bool, Char, Exc, Float, Func, Hash, Int, Keyword, LazySeq, List, MalFunc, Nil, Regex,
+    Set, Str, Sym, Vector,
+};
 
 #[derive(Debug, Clone)]
 pub enum MalVal {
     Nil,
     Bool(bool),
     Int(i64),
-    //Float(f64),
+    Float(f64),
     Str(String),
-    Sym(String),
-    List(Rc<Vec<MalVal>>, Rc<MalVal>),
-    Vector(Rc<Vec<MalVal>>, Rc<MalVal>),
-    Hash(Rc<FnvHashMap<String, MalVal>>, Rc<MalVal>),
-    Func(fn(MalArgs) -> MalRet, Rc<MalVal>),
+    Char(char),
+    // compared by name, so keywords made at runtime are freed with them
+    Keyword(Rc<str>),
+    Sym(Symbol),
+    List(MalSeq, Rc<MalVal>),
+    Vector(MalSeq, Rc<MalVal>),
+    Hash(MalMap, Rc<MalVal>),
+    Set(MalSet, Rc<MalVal>),
+    Func(fn(MalArgs) -> MalRet, &'static str, Arity, Rc<MalVal>),
     MalFunc {
         eval: fn(ast: MalVal, env: Env) -> MalRet,
         ast: Rc<MalVal>,
@@ -27,51 +37,877 @@ pub enum MalVal {
         params: Rc<MalVal>,
         is_macro: bool,
         meta: Rc<MalVal>,
+        // symbol the function was first def!'d to, Nil if anonymous
+        name: Rc<MalVal>,
     },
     Atom(Rc<RefCell<MalVal>>),
+    // exception built by ex-info or caught by catch*
+    Exc {
+        msg: String,
+        data: Rc<MalVal>,
+        cause: Rc<MalVal>,
+        // call frames active when it was first caught, None until then
+        trace: Option<Trace>,
+        // raised by the interpreter rather than built by ex-info
+        internal: bool,
+    },
+    // sequence whose cells are computed on first use and then cached
+    LazySeq(Rc<RefCell<Lazy>>),
+    Regex(Rc<regex::Regex>),
+}
+
+pub enum Lazy {
+    // computes the sequence: nil, any seqable value or another lazy seq
+    Thunk(Rc<dyn Fn() -> MalRet>),
+    // realized first element and rest of the sequence
+    Cell(MalVal, MalVal),
+    Empty,
 }
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
 pub enum MalErr {
     ErrString(String),
     ErrMalVal(MalVal),
+    // internal error caught as an exception whose data map has this :type
+    ErrKind(&'static str, String),
+    // error raised while evaluating a form the reader attached a position to
+    ErrAt(Box<MalErr>, MalVal),
+    // error carrying the mal call frames that were active when it was raised
+    ErrTrace(Box<MalErr>, Trace),
+}
+
+// A call to a mal function that has not returned yet
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub name: MalVal,
+    // the call form, Nil if the function was applied from rust
+    pub form: MalVal,
+}
+
+// Call frames, innermost first
+pub type Trace = Rc<Vec<Frame>>;
+
+thread_local! {
+    static FRAMES: RefCell<Vec<Frame>> = const { RefCell::new(Vec::new()) };
+    static SYMBOLS: RefCell<Symbols> = RefCell::new(Symbols::default());
+}
+
+// A symbol. Symbols compare and hash by id, so env lookups and special
+// form dispatch never touch the name. The names eval gives a meaning of
+// their own are predefined; any other name stays interned only while a
+// symbol with it is alive, and its id is reused once the last one drops.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Symbol(Name);
+
+#[derive(Clone, PartialEq, Eq)]
+enum Name {
+    Predefined(u32),
+    Interned(Rc<Interned>),
+}
+
+struct Interned {
+    id: u32,
+    name: Rc<str>,
+}
+
+impl PartialEq for Interned {
+    fn eq(&self, other: &Interned) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for Interned {}
+
+// Just the id: envs hash a symbol on every lookup
+impl StdHash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self.0 {
+            Name::Predefined(id) => state.write_u32(id),
+            Name::Interned(ref sym) => state.write_u32(sym.id),
+        }
+    }
+}
+
+impl Drop for Interned {
+    fn drop(&mut self) {
+        // The table may already be gone when a thread exits
+        let _ = SYMBOLS.try_with(|syms| {
+            if let Ok(mut syms) = syms.try_borrow_mut() {
+                syms.release(self);
+            }
+        });
+    }
+}
+
+// Holds names weakly, so dropping the last symbol frees its entry
+#[derive(Default)]
+struct Symbols {
+    ids: FnvHashMap<Rc<str>, Weak<Interned>>,
+    free: Vec<u32>,
+    next: u32,
+}
+
+impl Symbols {
+    fn intern(&mut self, name: &str) -> Symbol {
+        if let Some(sym) = Symbol::predefined(name) {
+            return sym;
+        }
+        if let Some(sym) = self.ids.get(name).and_then(Weak::upgrade) {
+            return Symbol(Name::Interned(sym));
+        }
+        let id = self.free.pop().unwrap_or_else(|| {
+            self.next += 1;
+            self.next - 1
+        });
+        let name: Rc<str> = Rc::from(name);
+        let sym = Rc::new(Interned {
+            id,
+            name: name.clone(),
+        });
+        self.ids.insert(name, Rc::downgrade(&sym));
+        Symbol(Name::Interned(sym))
+    }
+
+    fn release(&mut self, sym: &Interned) {
+        self.ids.remove(&sym.name);
+        self.free.push(sym.id);
+    }
+}
+
+impl Symbol {
+    pub fn new(name: &str) -> Symbol {
+        SYMBOLS.with(|syms| syms.borrow_mut().intern(name))
+    }
+
+    fn predefined(name: &str) -> Option<Symbol> {
+        let id = PREDEFINED.iter().position(|&p| p == name)?;
+        Some(Symbol(Name::Predefined(id as u32)))
+    }
+
+    pub fn name(&self) -> &str {
+        match self.0 {
+            Name::Predefined(id) => PREDEFINED[id as usize],
+            Name::Interned(ref sym) => &sym.name,
+        }
+    }
+
+    // The symbol named name, if there is one, without interning it
+    #[allow(dead_code)] // only stepA's REPL looks names up as they are typed
+    pub fn find(name: &str) -> Option<Symbol> {
+        if let Some(sym) = Symbol::predefined(name) {
+            return Some(sym);
+        }
+        SYMBOLS
+            .with(|syms| syms.borrow().ids.get(name).and_then(Weak::upgrade))
+            .map(|sym| Symbol(Name::Interned(sym)))
+    }
+}
+
+impl Deref for Symbol {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.name()
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Symbol({:?})", self.name())
+    }
+}
+
+// Never interned, so their ids are constants
+macro_rules! predefined_symbols {
+    ($($id:ident = $name:expr,)*) => {
+        #[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+        enum Predefined {
+            $($id,)*
+        }
+
+        pub const PREDEFINED: &[&str] = &[$($name,)*];
+
+        // Symbols eval gives a meaning of their own, matched by id
+        #[allow(dead_code)] // each step knows only the forms it implements
+        pub mod special {
+            use super::{Name, Predefined, Symbol};
+            $(pub const $id: Symbol = Symbol(Name::Predefined(Predefined::$id as u32));)*
+        }
+    };
+}
+
+predefined_symbols! {
+    DEF = "def!",
+    LET = "let*",
+    DO = "do",
+    IF = "if",
+    FN = "fn*",
+    EVAL = "eval",
+    QUOTE = "quote",
+    QUASIQUOTE = "quasiquote",
+    QUASIQUOTEEXPAND = "quasiquoteexpand",
+    UNQUOTE = "unquote",
+    SPLICE_UNQUOTE = "splice-unquote",
+    DEFMACRO = "defmacro!",
+    MACROEXPAND = "macroexpand",
+    TRY = "try*",
+    CATCH = "catch*",
+    FINALLY = "finally*",
+    LOOP = "loop*",
+    RECUR = "recur",
+    LAZY_SEQ = "lazy-seq",
+    AMP = "&",
+}
+
+// The symbol with this name
+pub fn sym(name: &str) -> MalVal {
+    Sym(Symbol::new(name))
+}
+
+// The keyword with this name (without the leading colon)
+pub fn keyword(name: &str) -> MalVal {
+    Keyword(Rc::from(name))
+}
+
+// Number of arguments a function accepts
+#[derive(Debug, Clone, Copy)]
+pub enum Arity {
+    Exactly(usize),
+    AtLeast(usize),
+    Between(usize, usize),
 }
 
 pub type MalArgs = Vec<MalVal>;
 pub type MalRet = Result<MalVal, MalErr>;
 
+// Persistent collections: clones share structure, so functional updates
+// like cons, conj and assoc don't copy the whole collection.
+pub type MalMap = im_rc::HashMap<MalVal, MalVal, FnvBuildHasher>;
+pub type MalSet = im_rc::HashSet<MalVal, FnvBuildHasher>;
+
+// The smallest im_rc vector node holds 64 elements, over 5KB for MalVals,
+// so sequences of up to SMALL_SEQ elements are kept in a plain shared Vec
+// that updates copy instead. Longer ones switch to an im_rc vector.
+const SMALL_SEQ: usize = 32;
+
+#[derive(Clone)]
+pub struct MalSeq(Seq);
+
+#[derive(Clone)]
+enum Seq {
+    Small(Rc<Vec<MalVal>>),
+    Large(im_rc::Vector<MalVal>),
+}
+
+impl MalSeq {
+    pub fn new() -> MalSeq {
+        MalSeq(Seq::Small(Rc::new(vec![])))
+    }
+
+    pub fn len(&self) -> usize {
+        match self.0 {
+            Seq::Small(ref v) => v.len(),
+            Seq::Large(ref v) => v.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> MalSeqIter<'_> {
+        match self.0 {
+            Seq::Small(ref v) => MalSeqIter::Small(v.iter()),
+            Seq::Large(ref v) => MalSeqIter::Large(v.iter()),
+        }
+    }
+
+    pub fn get(&self, i: usize) -> Option<&MalVal> {
+        match self.0 {
+            Seq::Small(ref v) => v.get(i),
+            Seq::Large(ref v) => v.get(i),
+        }
+    }
+
+    pub fn front(&self) -> Option<&MalVal> {
+        self.get(0)
+    }
+
+    pub fn last(&self) -> Option<&MalVal> {
+        match self.0 {
+            Seq::Small(ref v) => v.last(),
+            Seq::Large(ref v) => v.last(),
+        }
+    }
+
+    // The im_rc vector to update in place, once the sequence is or will
+    // grow past SMALL_SEQ elements
+    fn large(&mut self, grow: usize) -> Option<&mut im_rc::Vector<MalVal>> {
+        if let Seq::Small(ref v) = self.0 {
+            if v.len() + grow <= SMALL_SEQ {
+                return None;
+            }
+            self.0 = Seq::Large(v.iter().cloned().collect());
+        }
+        match self.0 {
+            Seq::Large(ref mut v) => Some(v),
+            Seq::Small(_) => None,
+        }
+    }
+
+    // The Vec of a small sequence, copied first if it is shared
+    fn small(&mut self) -> &mut Vec<MalVal> {
+        match self.0 {
+            Seq::Small(ref mut v) => Rc::make_mut(v),
+            Seq::Large(_) => unreachable!("MalSeq::small on a large sequence"),
+        }
+    }
+
+    pub fn push_front(&mut self, x: MalVal) {
+        match self.large(1) {
+            Some(v) => v.push_front(x),
+            None => self.small().insert(0, x),
+        }
+    }
+
+    pub fn push_back(&mut self, x: MalVal) {
+        match self.large(1) {
+            Some(v) => v.push_back(x),
+            None => self.small().push(x),
+        }
+    }
+
+    pub fn pop_front(&mut self) -> Option<MalVal> {
+        match self.0 {
+            Seq::Small(ref v) if v.is_empty() => None,
+            Seq::Small(ref mut v) => Some(Rc::make_mut(v).remove(0)),
+            Seq::Large(ref mut v) => v.pop_front(),
+        }
+    }
+
+    pub fn append(&mut self, other: MalSeq) {
+        match self.large(other.len()) {
+            Some(v) => v.append(other.into_iter().collect()),
+            None => self.small().extend(other),
+        }
+    }
+
+    // The elements from i on, like im_rc's skip
+    pub fn skip(&self, i: usize) -> MalSeq {
+        match self.0 {
+            Seq::Small(ref v) => v[i..].iter().cloned().collect(),
+            Seq::Large(ref v) => MalSeq::from(v.skip(i)),
+        }
+    }
+
+    // The first n elements, like im_rc's take
+    pub fn take(&self, n: usize) -> MalSeq {
+        match self.0 {
+            Seq::Small(ref v) => v[..n].iter().cloned().collect(),
+            Seq::Large(ref v) => MalSeq::from(v.take(n)),
+        }
+    }
+
+    // Replace the element at i, like im_rc's update
+    pub fn update(&self, i: usize, x: MalVal) -> MalSeq {
+        let mut seq = self.clone();
+        match seq.0 {
+            Seq::Small(ref mut v) => Rc::make_mut(v)[i] = x,
+            Seq::Large(ref mut v) => {
+                v.set(i, x);
+            }
+        }
+        seq
+    }
+}
+
+impl Default for MalSeq {
+    fn default() -> MalSeq {
+        MalSeq::new()
+    }
+}
+
+impl From<Vec<MalVal>> for MalSeq {
+    fn from(v: Vec<MalVal>) -> MalSeq {
+        if v.len() <= SMALL_SEQ {
+            MalSeq(Seq::Small(Rc::new(v)))
+        } else {
+            MalSeq(Seq::Large(im_rc::Vector::from(v)))
+        }
+    }
+}
+
+impl From<im_rc::Vector<MalVal>> for MalSeq {
+    fn from(v: im_rc::Vector<MalVal>) -> MalSeq {
+        if v.len() <= SMALL_SEQ {
+            MalSeq(Seq::Small(Rc::new(v.into_iter().collect())))
+        } else {
+            MalSeq(Seq::Large(v))
+        }
+    }
+}
+
+impl std::iter::FromIterator<MalVal> for MalSeq {
+    fn from_iter<I: IntoIterator<Item = MalVal>>(iter: I) -> MalSeq {
+        MalSeq::from(iter.into_iter().collect::<Vec<MalVal>>())
+    }
+}
+
+impl Extend<MalVal> for MalSeq {
+    fn extend<I: IntoIterator<Item = MalVal>>(&mut self, iter: I) {
+        for x in iter {
+            self.push_back(x);
+        }
+    }
+}
+
+impl std::ops::Index<usize> for MalSeq {
+    type Output = MalVal;
+
+    fn index(&self, i: usize) -> &MalVal {
+        match self.0 {
+            Seq::Small(ref v) => &v[i],
+            Seq::Large(ref v) => &v[i],
+        }
+    }
+}
+
+impl PartialEq for MalSeq {
+    fn eq(&self, other: &MalSeq) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+impl StdHash for MalSeq {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for x in self.iter() {
+            x.hash(state);
+        }
+    }
+}
+
+impl fmt::Debug for MalSeq {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+pub enum MalSeqIter<'a> {
+    Small(std::slice::Iter<'a, MalVal>),
+    Large(im_rc::vector::Iter<'a, MalVal>),
+}
+
+impl<'a> Iterator for MalSeqIter<'a> {
+    type Item = &'a MalVal;
+
+    fn next(&mut self) -> Option<&'a MalVal> {
+        match self {
+            MalSeqIter::Small(it) => it.next(),
+            MalSeqIter::Large(it) => it.next(),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self {
+            MalSeqIter::Small(it) => it.size_hint(),
+            MalSeqIter::Large(it) => it.size_hint(),
+        }
+    }
+}
+
+impl<'a> DoubleEndedIterator for MalSeqIter<'a> {
+    fn next_back(&mut self) -> Option<&'a MalVal> {
+        match self {
+            MalSeqIter::Small(it) => it.next_back(),
+            MalSeqIter::Large(it) => it.next_back(),
+        }
+    }
+}
+
+impl<'a> ExactSizeIterator for MalSeqIter<'a> {}
+
+impl<'a> IntoIterator for &'a MalSeq {
+    type Item = &'a MalVal;
+    type IntoIter = MalSeqIter<'a>;
+
+    fn into_iter(self) -> MalSeqIter<'a> {
+        self.iter()
+    }
+}
+
+pub enum MalSeqIntoIter {
+    Small(std::vec::IntoIter<MalVal>),
+    Large(im_rc::vector::ConsumingIter<MalVal>),
+}
+
+impl Iterator for MalSeqIntoIter {
+    type Item = MalVal;
+
+    fn next(&mut self) -> Option<MalVal> {
+        match self {
+            MalSeqIntoIter::Small(it) => it.next(),
+            MalSeqIntoIter::Large(it) => it.next(),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self {
+            MalSeqIntoIter::Small(it) => it.size_hint(),
+            MalSeqIntoIter::Large(it) => it.size_hint(),
+        }
+    }
+}
+
+impl DoubleEndedIterator for MalSeqIntoIter {
+    fn next_back(&mut self) -> Option<MalVal> {
+        match self {
+            MalSeqIntoIter::Small(it) => it.next_back(),
+            MalSeqIntoIter::Large(it) => it.next_back(),
+        }
+    }
+}
+
+impl IntoIterator for MalSeq {
+    type Item = MalVal;
+    type IntoIter = MalSeqIntoIter;
+
+    fn into_iter(self) -> MalSeqIntoIter {
+        match self.0 {
+            Seq::Small(v) => {
+                let v = Rc::try_unwrap(v).unwrap_or_else(|v| (*v).clone());
+                MalSeqIntoIter::Small(v.into_iter())
+            }
+            Seq::Large(v) => MalSeqIntoIter::Large(v.into_iter()),
+        }
+    }
+}
+
 // type utility macros
 
 macro_rules! list {
   ($seq:expr) => {{
-    List(Rc::new($seq),Rc::new(Nil))
+    let v: Vec<MalVal> = $seq;
+    List($crate::types::MalSeq::from(v),Rc::new(Nil))
   }};
   [$($args:expr),*] => {{
     let v: Vec<MalVal> = vec![$($args),*];
-    List(Rc::new(v),Rc::new(Nil))
+    List($crate::types::MalSeq::from(v),Rc::new(Nil))
   }}
 }
 
 macro_rules! vector {
   ($seq:expr) => {{
-    Vector(Rc::new($seq),Rc::new(Nil))
+    let v: Vec<MalVal> = $seq;
+    Vector($crate::types::MalSeq::from(v),Rc::new(Nil))
   }};
   [$($args:expr),*] => {{
     let v: Vec<MalVal> = vec![$($args),*];
-    Vector(Rc::new(v),Rc::new(Nil))
+    Vector($crate::types::MalSeq::from(v),Rc::new(Nil))
   }}
 }
 
 // type utility functions
 
+pub fn re_pattern(pattern: &str) -> MalRet {
+    match regex::Regex::new(pattern) {
+        Ok(re) => Ok(Regex(Rc::new(re))),
+        Err(e) => kind_error("regex", &format!("invalid regex: {}", e)),
+    }
+}
+
+pub fn lazy_seq(thunk: impl Fn() -> MalRet + 'static) -> MalVal {
+    LazySeq(Rc::new(RefCell::new(Lazy::Thunk(Rc::new(thunk)))))
+}
+
+// An already realized lazy seq cell, so rest is not forced
+pub fn lazy_cons(first: MalVal, rest: MalVal) -> MalVal {
+    LazySeq(Rc::new(RefCell::new(Lazy::Cell(first, rest))))
+}
+
+impl fmt::Debug for Lazy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Lazy::Thunk(_) => write!(f, "Thunk"),
+            Lazy::Cell(first, rest) => f.debug_tuple("Cell").field(first).field(rest).finish(),
+            Lazy::Empty => write!(f, "Empty"),
+        }
+    }
+}
+
+// Dropping a long realized seq would otherwise recurse once per cell
+impl Drop for Lazy {
+    fn drop(&mut self) {
+        let mut next = match *self {
+            Lazy::Cell(_, ref mut rest) => std::mem::replace(rest, Nil),
+            _ => return,
+        };
+        while let LazySeq(cell) = next {
+            next = match Rc::try_unwrap(cell) {
+                Ok(cell) => match *cell.borrow_mut() {
+                    Lazy::Cell(_, ref mut rest) => std::mem::replace(rest, Nil),
+                    _ => break,
+                },
+                Err(_) => break,
+            };
+        }
+    }
+}
+
+// Realize a lazy seq cell, caching the result
+fn force(cell: &Rc<RefCell<Lazy>>) -> Result<Option<(MalVal, MalVal)>, MalErr> {
+    let thunk = match *cell.borrow() {
+        Lazy::Thunk(ref thunk) => thunk.clone(),
+        Lazy::Cell(ref first, ref rest) => return Ok(Some((first.clone(), rest.clone()))),
+        Lazy::Empty => return Ok(None),
+    };
+    let mut v = thunk()?;
+    // a lazy-seq body that returns another lazy seq is followed in a loop,
+    // so long chains of them don't grow the stack
+    loop {
+        let inner = match v {
+            LazySeq(ref inner) => inner.clone(),
+            _ => break,
+        };
+        let thunk = match *inner.borrow() {
+            Lazy::Thunk(ref thunk) => thunk.clone(),
+            _ => break,
+        };
+        v = thunk()?;
+    }
+    let ret = v.uncons()?;
+    *cell.borrow_mut() = match ret {
+        Some((ref first, ref rest)) => Lazy::Cell(first.clone(), rest.clone()),
+        None => Lazy::Empty,
+    };
+    Ok(ret)
+}
+
+// Iterates the elements of any seqable value, stopping after an error
+pub struct SeqIter(Option<MalVal>);
+
+impl Iterator for SeqIter {
+    type Item = MalRet;
+
+    fn next(&mut self) -> Option<MalRet> {
+        match self.0.take()?.uncons() {
+            Ok(Some((first, rest))) => {
+                self.0 = Some(rest);
+                Some(Ok(first))
+            }
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
 pub fn error(s: &str) -> MalRet {
     Err(ErrString(s.to_string()))
 }
 
+pub fn kind_error(kind: &'static str, s: &str) -> MalRet {
+    Err(ErrKind(kind, s.to_string()))
+}
+
+const MAX_TRACE_LINES: usize = 50;
+
 pub fn format_error(e: MalErr) -> String {
     match e {
-        ErrString(s) => s.clone(),
+        ErrString(s) | ErrKind(_, s) => s.clone(),
+        ErrMalVal(Exc { msg, .. }) => msg,
         ErrMalVal(mv) => mv.pr_str(true),
+        ErrAt(e, pos) => format!("{} at {}", format_error(*e), pr_pos(&pos)),
+        ErrTrace(e, trace) => {
+            let mut s = format_error(*e);
+            let lines: Vec<String> = trace.iter().map(pr_frame).collect();
+            // collapse runs of identical frames and cap what deep recursion prints
+            let mut i = 0;
+            let mut shown = 0;
+            while i < lines.len() {
+                if shown == MAX_TRACE_LINES {
+                    s.push_str("\n  ...");
+                    break;
+                }
+                let run = lines[i..].iter().take_while(|l| **l == lines[i]).count();
+                s.push_str(&format!("\n  in {}", lines[i]));
+                if run > 1 {
+                    s.push_str(&format!(
+                        "\n  [previous frame repeated {} more times]",
+                        run - 1
+                    ));
+                }
+                i += run;
+                shown += 1;
+            }
+            s
+        }
+    }
+}
+
+// Meta map the reader attaches to forms read from a named source
+pub fn src_pos(file: &str, line: usize, column: usize) -> MalVal {
+    let mut hm = MalMap::default();
+    hm.insert(keyword("file"), Str(file.to_string()));
+    hm.insert(keyword("line"), Int(line as i64));
+    hm.insert(keyword("column"), Int(column as i64));
+    Hash(hm, Rc::new(Nil))
+}
+
+pub fn is_src_pos(meta: &MalVal) -> bool {
+    thread_local! {
+        // checked on every list eval, so not allocated each time
+        static LINE: MalVal = keyword("line");
+    }
+    match meta {
+        Hash(hm, _) => LINE.with(|line| hm.contains_key(line)),
+        _ => false,
+    }
+}
+
+fn pr_pos(pos: &MalVal) -> String {
+    match pos {
+        Hash(hm, _) => {
+            let get = |k: &str| {
+                hm.get(&keyword(k))
+                    .map(|v| v.pr_str(false))
+                    .unwrap_or_default()
+            };
+            format!("{}:{}:{}", get("file"), get("line"), get("column"))
+        }
+        _ => pos.pr_str(false),
+    }
+}
+
+fn pr_frame(frame: &Frame) -> String {
+    let name = match frame.name {
+        Nil => "anonymous fn".to_string(),
+        ref name => name.pr_str(false),
+    };
+    match frame.form {
+        Nil => name,
+        List(_, ref meta) if is_src_pos(meta) => {
+            format!("{}: {} at {}", name, frame.form.pr_str(true), pr_pos(meta))
+        }
+        ref form => format!("{}: {}", name, form.pr_str(true)),
+    }
+}
+
+// Number of active call frames
+#[allow(dead_code)] // only stepA records frames from eval
+pub fn frame_depth() -> usize {
+    FRAMES.with(|frames| frames.borrow().len())
+}
+
+pub fn push_frame(frame: Frame) {
+    FRAMES.with(|frames| frames.borrow_mut().push(frame));
+}
+
+// Replace the innermost frame, used for tail calls
+#[allow(dead_code)] // only stepA records frames from eval
+pub fn replace_frame(frame: Frame) {
+    FRAMES.with(|frames| {
+        let mut frames = frames.borrow_mut();
+        frames.pop();
+        frames.push(frame);
+    });
+}
+
+#[allow(dead_code)] // only stepA records frames from eval
+pub fn truncate_frames(depth: usize) {
+    FRAMES.with(|frames| frames.borrow_mut().truncate(depth));
+}
+
+// The frames recorded when an exception was thrown, nil for anything that
+// is not an exception or has not been thrown yet
+pub fn stack_trace(exc: &MalVal) -> MalVal {
+    match exc {
+        Exc {
+            trace: Some(trace), ..
+        } => list!(trace.iter().map(|f| Str(pr_frame(f))).collect()),
+        _ => Nil,
+    }
+}
+
+impl MalErr {
+    // Tag the error with a source position unless it already has one
+    pub fn at(self, pos: &MalVal) -> MalErr {
+        match self {
+            ErrAt(..) => self,
+            ErrTrace(e, trace) => ErrTrace(Box::new(e.at(pos)), trace),
+            _ if is_src_pos(pos) => ErrAt(Box::new(self), pos.clone()),
+            _ => self,
+        }
+    }
+
+    // The value a catch* block binds for this error. An exception keeps the
+    // frames of the first throw, so rethrowing it does not replace them.
+    #[allow(dead_code)] // only the steps with try* use it
+    pub fn exc(&self) -> MalVal {
+        match self {
+            ErrString(s) => internal_error("error", s),
+            ErrKind(kind, s) => internal_error(kind, s),
+            ErrMalVal(mv) => mv.clone(),
+            ErrAt(e, _) => e.exc(),
+            ErrTrace(e, trace) => match e.exc() {
+                Exc {
+                    msg,
+                    data,
+                    cause,
+                    trace: None,
+                    internal,
+                } => Exc {
+                    msg,
+                    data,
+                    cause,
+                    trace: Some(trace.clone()),
+                    internal,
+                },
+                exc => exc,
+            },
+        }
+    }
+
+    // Record the active call frames unless the error already carries them
+    #[allow(dead_code)] // only stepA records frames from eval
+    pub fn traced(self) -> MalErr {
+        match self {
+            ErrTrace(..) => self,
+            _ => {
+                let trace = FRAMES.with(|frames| frames.borrow().iter().rev().cloned().collect());
+                ErrTrace(Box::new(self), Rc::new(trace))
+            }
+        }
+    }
+}
+
+impl Arity {
+    pub fn check(&self, name: &str, nargs: usize) -> Result<(), MalErr> {
+        let ok = match *self {
+            Arity::Exactly(n) => nargs == n,
+            Arity::AtLeast(n) => nargs >= n,
+            Arity::Between(lo, hi) => lo <= nargs && nargs <= hi,
+        };
+        if ok {
+            return Ok(());
+        }
+        let expected = match *self {
+            Arity::Exactly(n) => format!("{}", n),
+            Arity::AtLeast(n) => format!("at least {}", n),
+            Arity::Between(lo, hi) => format!("{} to {}", lo, hi),
+        };
+        Err(ErrKind(
+            "arity",
+            format!(
+                "wrong number of args ({}) passed to {}, expected {}",
+                nargs, name, expected
+            ),
+        ))
     }
 }
 
@@ -82,8 +918,8 @@ pub fn atom(mv: &MalVal) -> MalVal {
 impl MalVal {
     pub fn keyword(&self) -> MalRet {
         match self {
-            Str(s) if s.starts_with("\u{29e}") => Ok(Str(s.to_string())),
-            Str(s) => Ok(Str(format!("\u{29e}{}", s))),
+            Keyword(_) => Ok(self.clone()),
+            Str(s) => Ok(keyword(s)),
             _ => error("invalid type for keyword"),
         }
     }
@@ -91,6 +927,10 @@ impl MalVal {
     pub fn empty_q(&self) -> MalRet {
         match self {
             List(l, _) | Vector(l, _) => Ok(Bool(l.len() == 0)),
+            Hash(hm, _) => Ok(Bool(hm.is_empty())),
+            Set(hs, _) => Ok(Bool(hs.is_empty())),
+            Str(s) => Ok(Bool(s.is_empty())),
+            LazySeq(_) => Ok(Bool(self.uncons()?.is_none())),
             Nil => Ok(Bool(true)),
             _ => error("invalid type for empty?"),
         }
@@ -99,37 +939,115 @@ impl MalVal {
     pub fn count(&self) -> MalRet {
         match self {
             List(l, _) | Vector(l, _) => Ok(Int(l.len() as i64)),
+            Hash(hm, _) => Ok(Int(hm.len() as i64)),
+            Set(hs, _) => Ok(Int(hs.len() as i64)),
+            Str(s) => Ok(Int(s.chars().count() as i64)),
+            LazySeq(_) => {
+                let mut n = 0;
+                for x in self.iter() {
+                    x?;
+                    n += 1;
+                }
+                Ok(Int(n))
+            }
             Nil => Ok(Int(0)),
             _ => error("invalid type for count"),
         }
     }
 
+    // First element and rest of any seqable value, None when it is empty.
+    // Maps seq as [key value] entries and strings as characters.
+    pub fn uncons(&self) -> Result<Option<(MalVal, MalVal)>, MalErr> {
+        match self {
+            List(l, _) | Vector(l, _) => Ok(l
+                .front()
+                .map(|first| (first.clone(), List(l.skip(1), Rc::new(Nil))))),
+            LazySeq(cell) => force(cell),
+            Nil => Ok(None),
+            Hash(..) | Set(..) => List(self.elements()?, Rc::new(Nil)).uncons(),
+            Str(_) => List(self.elements()?, Rc::new(Nil)).uncons(),
+            _ => Err(ErrString(format!("{} is not seqable", self.pr_str(true)))),
+        }
+    }
+
+    // Iterate the elements of any seqable value
+    pub fn iter(&self) -> SeqIter {
+        SeqIter(Some(self.clone()))
+    }
+
+    // The elements of any seqable value, realizing lazy seqs
+    pub fn elements(&self) -> Result<MalSeq, MalErr> {
+        match self {
+            List(l, _) | Vector(l, _) => Ok(l.clone()),
+            Hash(hm, _) => Ok(hm
+                .iter()
+                .map(|(k, v)| vector![k.clone(), v.clone()])
+                .collect()),
+            Set(hs, _) => Ok(hs.iter().cloned().collect()),
+            Str(s) => Ok(s.chars().map(Char).collect()),
+            _ => self.iter().collect(),
+        }
+    }
+
+    // Realize every lazy seq nested in the value, so printing and comparing
+    // it cannot fail
+    pub fn realize(&self) -> Result<(), MalErr> {
+        match self {
+            List(l, _) | Vector(l, _) => l.iter().try_for_each(|x| x.realize()),
+            Hash(hm, _) => hm.values().try_for_each(|x| x.realize()),
+            LazySeq(_) => self.iter().try_for_each(|x| x?.realize()),
+            _ => Ok(()),
+        }
+    }
+
     pub fn apply(&self, args: MalArgs) -> MalRet {
         match *self {
-            Func(f, _) => f(args),
+            Func(f, name, arity, _) => {
+                arity.check(name, args.len())?;
+                f(args)
+            }
             MalFunc {
                 eval,
                 ref ast,
                 ref env,
                 ref params,
+                ref name,
                 ..
             } => {
                 let a = &**ast;
                 let p = &**params;
-                let fn_env = env_bind(Some(env.clone()), p.clone(), args)?;
-                Ok(eval(a.clone(), fn_env)?)
+                let fn_env = env_bind(Some(env.clone()), p.clone(), args, name)?;
+                push_frame(Frame {
+                    name: (**name).clone(),
+                    form: Nil,
+                });
+                let ret = eval(a.clone(), fn_env);
+                FRAMES.with(|frames| frames.borrow_mut().pop());
+                ret
+            }
+            // a keyword called on a map looks itself up in it
+            Keyword(_) => {
+                Arity::Between(1, 2).check("keyword", args.len())?;
+                let found = match args[0] {
+                    Hash(ref hm, _) => hm.get(self).cloned(),
+                    Set(ref hs, _) => Some(self.clone()).filter(|k| hs.contains(k)),
+                    _ => None,
+                };
+                Ok(found.or_else(|| args.get(1).cloned()).unwrap_or(Nil))
+            }
+            // a set called on a value returns it if it is a member, else nil
+            Set(ref hs, _) => {
+                Arity::Exactly(1).check("set", args.len())?;
+                Ok(if hs.contains(&args[0]) {
+                    args[0].clone()
+                } else {
+                    Nil
+                })
             }
             _ => error("attempt to call non-function"),
         }
     }
 
-    pub fn keyword_q(&self) -> bool {
-        match self {
-            Str(s) if s.starts_with("\u{29e}") => true,
-            _ => false,
-        }
-    }
-
     pub fn deref(&self) -> MalRet {
         match self {
             Atom(a) => Ok(a.borrow().clone()),
@@ -160,10 +1078,38 @@ impl MalVal {
         }
     }
 
+    // Name an anonymous function after the symbol it is def!'d to
+    #[allow(dead_code)] // only stepA records frames from eval
+    pub fn named(self, sym: &MalVal) -> MalVal {
+        match self {
+            MalFunc {
+                eval,
+                ast,
+                env,
+                params,
+                is_macro,
+                meta,
+                name,
+            } => MalFunc {
+                eval,
+                ast,
+                env,
+                params,
+                is_macro,
+                meta,
+                name: match *name {
+                    Nil => Rc::new(sym.clone()),
+                    _ => name,
+                },
+            },
+            _ => self,
+        }
+    }
+
     pub fn get_meta(&self) -> MalRet {
         match self {
-            List(_, meta) | Vector(_, meta) | Hash(_, meta) => Ok((&**meta).clone()),
-            Func(_, meta) => Ok((&**meta).clone()),
+            List(_, meta) | Vector(_, meta) | Hash(_, meta) | Set(_, meta) => Ok((&**meta).clone()),
+            Func(.., meta) => Ok((&**meta).clone()),
             MalFunc { meta, .. } => Ok((&**meta).clone()),
             _ => error("meta not supported by type"),
         }
@@ -174,7 +1120,8 @@ impl MalVal {
             List(_, ref mut meta)
             | Vector(_, ref mut meta)
             | Hash(_, ref mut meta)
-            | Func(_, ref mut meta)
+            | Set(_, ref mut meta)
+            | Func(.., ref mut meta)
             | MalFunc { ref mut meta, .. } => {
                 *meta = Rc::new((&*new_meta).clone());
             }
@@ -190,51 +1137,224 @@ impl PartialEq for MalVal {
             (Nil, Nil) => true,
             (Bool(ref a), Bool(ref b)) => a == b,
             (Int(ref a), Int(ref b)) => a == b,
+            (Float(ref a), Float(ref b)) => a == b,
             (Str(ref a), Str(ref b)) => a == b,
+            (Char(ref a), Char(ref b)) => a == b,
+            (Keyword(ref a), Keyword(ref b)) => a == b,
             (Sym(ref a), Sym(ref b)) => a == b,
             (List(ref a, _), List(ref b, _))
             | (Vector(ref a, _), Vector(ref b, _))
             | (List(ref a, _), Vector(ref b, _))
             | (Vector(ref a, _), List(ref b, _)) => a == b,
             (Hash(ref a, _), Hash(ref b, _)) => a == b,
-            (MalFunc { .. }, MalFunc { .. }) => false,
+            (Set(ref a, _), Set(ref b, _)) => a == b,
+            // a lazy seq that fails to realize is unequal to everything
+            (LazySeq(_), List(..))
+            | (LazySeq(_), Vector(..))
+            | (LazySeq(_), LazySeq(_))
+            | (List(..), LazySeq(_))
+            | (Vector(..), LazySeq(_)) => {
+                let (mut a, mut b) = (self.iter(), other.iter());
+                loop {
+                    match (a.next(), b.next()) {
+                        (None, None) => return true,
+                        (Some(Ok(x)), Some(Ok(y))) if x == y => continue,
+                        _ => return false,
+                    }
+                }
+            }
+            (
+                Exc {
+                    msg: ref am,
+                    data: ref ad,
+                    cause: ref ac,
+                    ..
+                },
+                Exc {
+                    msg: ref bm,
+                    data: ref bd,
+                    cause: ref bc,
+                    ..
+                },
+            ) => am == bm && ad == bd && ac == bc,
+            // functions, atoms and regexes are only equal to themselves
+            (Func(_, a, ..), Func(_, b, ..)) => a == b,
+            (
+                MalFunc {
+                    ast: ref a_ast,
+                    env: ref a_env,
+                    ..
+                },
+                MalFunc {
+                    ast: ref b_ast,
+                    env: ref b_env,
+                    ..
+                },
+            ) => Rc::ptr_eq(a_ast, b_ast) && Rc::ptr_eq(a_env, b_env),
+            (Atom(ref a), Atom(ref b)) => Rc::ptr_eq(a, b),
+            (Regex(ref a), Regex(ref b)) => Rc::ptr_eq(a, b),
             _ => false,
         }
     }
 }
 
-pub fn func(f: fn(MalArgs) -> MalRet) -> MalVal {
-    Func(f, Rc::new(Nil))
+// Hash maps are keyed by any value. NaN is the only value not equal to
+// itself, so it can be stored as a key but never found again.
+impl Eq for MalVal {}
+
+// Consistent with PartialEq: meta is ignored, lists and vectors with the
+// same elements hash alike, and maps and sets hash independently of entry
+// order.
+impl StdHash for MalVal {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Nil => state.write_u8(0),
+            Bool(b) => {
+                state.write_u8(1);
+                b.hash(state);
+            }
+            Int(i) => {
+                state.write_u8(2);
+                i.hash(state);
+            }
+            Float(f) => {
+                state.write_u8(3);
+                // 0.0 and -0.0 are equal
+                (if *f == 0.0 { 0.0f64 } else { *f }).to_bits().hash(state);
+            }
+            Str(s) => {
+                state.write_u8(4);
+                s.hash(state);
+            }
+            Sym(s) => {
+                state.write_u8(5);
+                s.hash(state);
+            }
+            Char(c) => {
+                state.write_u8(14);
+                c.hash(state);
+            }
+            // by name rather than pointer, so map order is the same every run
+            Keyword(k) => {
+                state.write_u8(15);
+                k.hash(state);
+            }
+            List(v, _) | Vector(v, _) => {
+                state.write_u8(6);
+                v.hash(state);
+            }
+            // hashes like a list of the elements realized without error
+            LazySeq(_) => {
+                state.write_u8(6);
+                for x in self.iter() {
+                    match x {
+                        Ok(x) => x.hash(state),
+                        Err(_) => break,
+                    }
+                }
+            }
+            Hash(hm, _) => {
+                state.write_u8(7);
+                let mut sum: u64 = 0;
+                for entry in hm.iter() {
+                    let mut h = FnvHasher::default();
+                    entry.hash(&mut h);
+                    sum = sum.wrapping_add(h.finish());
+                }
+                state.write_u64(sum);
+            }
+            Func(_, name, ..) => {
+                state.write_u8(8);
+                name.hash(state);
+            }
+            MalFunc { ast, .. } => {
+                state.write_u8(9);
+                (Rc::as_ptr(ast) as usize).hash(state);
+            }
+            Atom(a) => {
+                state.write_u8(10);
+                (Rc::as_ptr(a) as usize).hash(state);
+            }
+            Regex(re) => {
+                state.write_u8(13);
+                (Rc::as_ptr(re) as usize).hash(state);
+            }
+            Exc {
+                msg, data, cause, ..
+            } => {
+                state.write_u8(11);
+                msg.hash(state);
+                data.hash(state);
+                cause.hash(state);
+            }
+            Set(hs, _) => {
+                state.write_u8(12);
+                let mut sum: u64 = 0;
+                for v in hs.iter() {
+                    let mut h = FnvHasher::default();
+                    v.hash(&mut h);
+                    sum = sum.wrapping_add(h.finish());
+                }
+                state.write_u64(sum);
+            }
+        }
+    }
+}
+
+pub fn func(name: &'static str, arity: Arity, f: fn(MalArgs) -> MalRet) -> MalVal {
+    Func(f, name, arity, Rc::new(Nil))
+}
+
+pub fn ex_info(msg: &str, data: MalVal, cause: MalVal) -> MalVal {
+    Exc {
+        msg: msg.to_string(),
+        data: Rc::new(data),
+        cause: Rc::new(cause),
+        trace: None,
+        internal: false,
+    }
+}
+
+#[allow(dead_code)] // only the steps with try* use it
+fn internal_error(kind: &str, msg: &str) -> MalVal {
+    Exc {
+        msg: msg.to_string(),
+        data: Rc::new(kind_data(kind)),
+        cause: Rc::new(Nil),
+        trace: None,
+        internal: true,
+    }
+}
+
+// Data map of an internal error: {:type :<kind>}
+#[allow(dead_code)] // only the steps with try* use it
+fn kind_data(kind: &str) -> MalVal {
+    let mut hm = MalMap::default();
+    hm.insert(keyword("type"), keyword(kind));
+    Hash(hm, Rc::new(Nil))
 }
 
-pub fn _assoc(mut hm: FnvHashMap<String, MalVal>, kvs: MalArgs) -> MalRet {
+pub fn _assoc(mut hm: MalMap, kvs: MalArgs) -> MalRet {
     if kvs.len() % 2 != 0 {
         return error("odd number of elements");
     }
     for (k, v) in kvs.iter().tuples() {
-        match k {
-            Str(s) => {
-                hm.insert(s.to_string(), v.clone());
-            }
-            _ => return error("key is not string"),
-        }
+        hm.insert(k.clone(), v.clone());
     }
-    Ok(Hash(Rc::new(hm), Rc::new(Nil)))
+    Ok(Hash(hm, Rc::new(Nil)))
 }
 
-pub fn _dissoc(mut hm: FnvHashMap<String, MalVal>, ks: MalArgs) -> MalRet {
+pub fn _dissoc(mut hm: MalMap, ks: MalArgs) -> MalRet {
     for k in ks.iter() {
-        match k {
-            Str(ref s) => {
-                hm.remove(s);
-            }
-            _ => return error("key is not string"),
-        }
+        hm.remove(k);
     }
-    Ok(Hash(Rc::new(hm), Rc::new(Nil)))
+    Ok(Hash(hm, Rc::new(Nil)))
 }
 
 pub fn hash_map(kvs: MalArgs) -> MalRet {
-    let hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
-    _assoc(hm, kvs)
+    _assoc(MalMap::default(), kvs)
+}
+
+pub fn hash_set(vs: MalArgs) -> MalVal {
+    Set(vs.into_iter().collect(), Rc::new(Nil))
 }